@@ -14,10 +14,10 @@ extern crate clap;
 extern crate ldap3;
 
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use ldap3::{LdapConn, LdapConnSettings, Scope, SearchEntry};
 
 #[derive(serde_derive::Deserialize)]
@@ -41,6 +41,13 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .value_name("NAME")
                 .help("Config file (.json)"),
         )
+        .arg(
+            Arg::new("display_name")
+                .long("display-name")
+                .short('d')
+                .action(ArgAction::SetTrue)
+                .help("Print display names (Alen Smith <alen@example.com>)"),
+        )
         .arg(
             Arg::new("filter")
                 .long("filter")
@@ -53,9 +60,16 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .long("recipients")
                 .short('r')
                 .value_name("LIST")
-                .help("Recipients list (alen,cc:bob@example.com)")
+                .help("Recipients list (alen,cc:bob@example.com,bcc:catherine)")
                 .required(true),
         )
+        .arg(
+            Arg::new("suppression")
+                .long("suppression")
+                .short('s')
+                .value_name("NAME")
+                .help("Suppression list file with one address per line"),
+        )
         .get_matches();
 
     let default = "".to_string();
@@ -67,15 +81,21 @@ fn main() -> Result<(), Box<dyn Error>> {
     let filter = parse_filter(&config, f.as_str())?;
 
     let r = app.get_one("recipients").unwrap_or(&default);
-    let (mut cc, mut to) = parse_recipients(&config, r.as_str());
-    if cc.len() == 0 && to.len() == 0 {
+    let (mut bcc, mut cc, mut to) = parse_recipients(&config, r.as_str());
+    if bcc.len() == 0 && cc.len() == 0 && to.len() == 0 {
         return Err(Box::from("failed to parse recipients"));
     }
 
-    cc = fetch_address(&config, cc)?;
-    to = fetch_address(&config, to)?;
+    let display = app.get_flag("display_name");
+
+    bcc = fetch_address(&config, bcc, display)?;
+    cc = fetch_address(&config, cc, display)?;
+    to = fetch_address(&config, to, display)?;
 
-    print_address(cc, to, filter);
+    let s = app.get_one("suppression").unwrap_or(&default);
+    let suppression = parse_suppression(s.as_str())?;
+
+    print_address(bcc, cc, to, filter, suppression);
 
     return Ok(());
 }
@@ -109,31 +129,60 @@ fn parse_filter(config: &Config, data: &str) -> Result<Vec<String>, Box<dyn Erro
     return Ok(buf);
 }
 
-fn parse_recipients(config: &Config, data: &str) -> (Vec<String>, Vec<String>) {
+fn parse_suppression(name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut buf: Vec<String> = vec![];
+
+    if name.is_empty() {
+        return Ok(buf);
+    }
+
+    for line in fs::read_to_string(name)?.lines() {
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            buf.push(normalize_address(line).to_lowercase());
+        }
+    }
+
+    return Ok(buf);
+}
+
+fn parse_recipients(config: &Config, data: &str) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let mut bcc = Vec::new();
     let mut cc = Vec::new();
     let mut to = Vec::new();
 
-    for item in data.split(&config.sep) {
+    for item in split_recipients(data, &config.sep) {
         if !item.is_empty() {
-            if item.starts_with("cc:") {
-                let recipient = item.trim_start_matches("cc:");
+            if item.starts_with("bcc:") {
+                let recipient = item.trim_start_matches("bcc:").trim();
+                if !recipient.is_empty() {
+                    bcc.push(recipient.to_owned());
+                }
+            } else if item.starts_with("cc:") {
+                let recipient = item.trim_start_matches("cc:").trim();
                 if !recipient.is_empty() {
                     cc.push(recipient.to_owned());
                 }
             } else {
-                to.push(item.to_owned());
+                to.push(item);
             }
         }
     }
 
+    bcc = remove_duplicates(bcc);
     cc = remove_duplicates(cc);
     to = remove_duplicates(to);
     cc = collect_difference(cc, to.to_owned());
+    bcc = collect_difference(bcc, [to.to_owned(), cc.to_owned()].concat());
 
-    return (cc, to);
+    return (bcc, cc, to);
 }
 
-fn fetch_address(config: &Config, data: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
+fn fetch_address(
+    config: &Config,
+    data: Vec<String>,
+    display: bool,
+) -> Result<Vec<String>, Box<dyn Error>> {
     let fetch = |data: String| -> String {
         let buf: Vec<&str> = data.split("@").collect();
         if buf.len() == 0 {
@@ -142,7 +191,7 @@ fn fetch_address(config: &Config, data: Vec<String>) -> Result<Vec<String>, Box<
         return buf[0].to_string();
     };
 
-    let query = |filter: &str, data: String| -> Result<String, Box<dyn Error>> {
+    let query = |filter: &str, data: String| -> Result<(String, String), Box<dyn Error>> {
         let mut ldap: LdapConn = LdapConn::with_settings(
             LdapConnSettings::new()
                 .set_no_tls_verify(true)
@@ -161,67 +210,189 @@ fn fetch_address(config: &Config, data: Vec<String>) -> Result<Vec<String>, Box<
         if entry.len() == 0 {
             return Err(Box::from("failed to search"));
         }
-        let buf = SearchEntry::construct(entry[0].to_owned())
-            .attrs
+        let attrs = SearchEntry::construct(entry[0].to_owned()).attrs;
+        let buf = attrs
             .get("mail")
             .and_then(|ary| ary.first())
             .map(String::from);
+        let name = attrs
+            .get("displayName")
+            .and_then(|ary| ary.first())
+            .map(String::from)
+            .unwrap_or_default();
         ldap.unbind()?;
-        return Ok(buf.unwrap());
+        return Ok((buf.unwrap(), name));
     };
 
     let mut buf: Vec<String> = vec![];
 
     for item in data {
+        let address = parse_address(&item);
         let mut addr = "".to_string();
-        match query("mail", item.to_owned()) {
-            Ok(a) => addr = a,
+        let mut name = "".to_string();
+        match query("mail", address.to_owned()) {
+            Ok((a, n)) => (addr, name) = (a, n),
             Err(_) => {
-                if let Ok(a) = query("sAMAccountName", fetch(item.to_owned())) {
-                    addr = a;
+                if let Ok((a, n)) = query("sAMAccountName", fetch(address.to_owned())) {
+                    (addr, name) = (a, n);
                 }
             }
         }
-        if !addr.is_empty() {
-            buf.push(addr.to_owned())
+        if addr.is_empty() {
+            continue;
         }
+        if address != item.trim() {
+            name = parse_name(&item);
+        } else if !display {
+            name = "".to_string();
+        }
+        buf.push(format_mailbox(&name, &addr));
     }
 
     return Ok(buf);
 }
 
-fn print_address(cc: Vec<String>, to: Vec<String>, filter: Vec<String>) {
-    let mut cc = remove_duplicates(cc);
-    let to = remove_duplicates(to);
+fn print_address(
+    bcc: Vec<String>,
+    cc: Vec<String>,
+    to: Vec<String>,
+    filter: Vec<String>,
+    suppression: Vec<String>,
+) {
+    let mut bcc = remove_suppressed(remove_duplicates(bcc), &suppression);
+    let mut cc = remove_suppressed(remove_duplicates(cc), &suppression);
+    let to = remove_suppressed(remove_duplicates(to), &suppression);
 
     cc = collect_difference(cc, to.to_owned());
+    bcc = collect_difference(bcc, [to.to_owned(), cc.to_owned()].concat());
+
+    let mut buf: Vec<String> = vec![];
 
     for item in to {
         if let Ok(()) = filter_address(item.to_owned(), filter.to_owned()) {
-            print!("{},", item);
+            buf.push(item);
+        }
+    }
+
+    for item in cc {
+        if let Ok(()) = filter_address(item.to_owned(), filter.to_owned()) {
+            buf.push(format!("cc:{}", item));
+        }
+    }
+
+    for item in bcc {
+        if let Ok(()) = filter_address(item.to_owned(), filter.to_owned()) {
+            buf.push(format!("bcc:{}", item));
         }
     }
 
-    if cc.is_empty() {
+    if buf.is_empty() {
         return;
     }
 
-    for i in 0..cc.len() - 1 {
-        if let Ok(()) = filter_address(cc[i].to_owned(), filter.to_owned()) {
-            print!("cc:{},", cc[i]);
+    println!("{}", buf.join(","));
+}
+
+fn split_recipients(data: &str, sep: &str) -> Vec<String> {
+    let mut buf = Vec::new();
+    let mut item = String::new();
+    let mut escape = false;
+    let mut quote = false;
+    let mut rest = data;
+
+    while let Some(c) = rest.chars().next() {
+        if !quote && !sep.is_empty() && rest.starts_with(sep) {
+            buf.push(item.trim().to_string());
+            item.clear();
+            rest = &rest[sep.len()..];
+            continue;
         }
+        if escape {
+            escape = false;
+        } else if quote && c == '\\' {
+            escape = true;
+        } else if c == '"' {
+            quote = !quote;
+        }
+        item.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    buf.push(item.trim().to_string());
+
+    return buf;
+}
+
+fn parse_address(data: &str) -> String {
+    match (data.rfind('<'), data.rfind('>')) {
+        (Some(s), Some(e)) if s < e => data[s + 1..e].trim().to_string(),
+        _ => data.trim().to_string(),
     }
+}
+
+fn parse_name(data: &str) -> String {
+    let buf = match data.rfind('<') {
+        Some(s) => data[..s].trim(),
+        None => return "".to_string(),
+    };
 
-    if let Ok(()) = filter_address(cc[cc.len() - 1].to_owned(), filter.to_owned()) {
-        println!("cc:{}", cc[cc.len() - 1]);
+    match buf.strip_prefix('"').and_then(|b| b.strip_suffix('"')) {
+        Some(b) => b.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => buf.to_string(),
     }
 }
 
+fn normalize_address(data: &str) -> String {
+    let address = parse_address(data);
+
+    // Domains are case-insensitive and compared in punycode (RFC 5890)
+    match address.rsplit_once('@') {
+        Some((local, domain)) => match idna::domain_to_ascii(domain) {
+            Ok(d) => format!("{}@{}", local, d),
+            Err(_) => format!("{}@{}", local, domain.to_lowercase()),
+        },
+        None => address,
+    }
+}
+
+fn format_mailbox(name: &str, address: &str) -> String {
+    let name = name.trim();
+
+    if name.is_empty() {
+        return address.to_string();
+    }
+
+    if name.chars().any(|c| "()<>[]:;@\\,.\"".contains(c)) {
+        let buf = name.replace('\\', "\\\\").replace('"', "\\\"");
+        return format!("\"{}\" <{}>", buf, address);
+    }
+
+    return format!("{} <{}>", name, address);
+}
+
 fn remove_duplicates(data: Vec<String>) -> Vec<String> {
-    let mut buf = Vec::new();
+    let mut buf: Vec<String> = Vec::new();
+    let mut key = Vec::new();
 
     for item in data {
-        if !buf.contains(&item) {
+        let address = normalize_address(&item);
+        if !key.contains(&address) {
+            key.push(address);
+            buf.push(item);
+        }
+    }
+
+    return buf;
+}
+
+fn remove_suppressed(data: Vec<String>, suppression: &[String]) -> Vec<String> {
+    let mut buf: Vec<String> = vec![];
+
+    for item in data {
+        // Opt-outs match regardless of local part case
+        if suppression.contains(&normalize_address(&item).to_lowercase()) {
+            eprintln!("suppressed: {}", item);
+        } else {
             buf.push(item);
         }
     }
@@ -234,13 +405,14 @@ fn collect_difference(data: Vec<String>, other: Vec<String>) -> Vec<String> {
     let mut key = Vec::new();
 
     for item in other {
-        if !key.contains(&item) {
-            key.push(item);
+        let address = normalize_address(&item);
+        if !key.contains(&address) {
+            key.push(address);
         }
     }
 
     for item in data {
-        if !key.contains(&item) {
+        if !key.contains(&normalize_address(&item)) {
             buf.push(item);
         }
     }
@@ -250,8 +422,10 @@ fn collect_difference(data: Vec<String>, other: Vec<String>) -> Vec<String> {
 
 fn filter_address(data: String, filter: Vec<String>) -> Result<(), Box<dyn Error>> {
     let mut res = Err("filter failed".into());
+    let data = normalize_address(&data);
 
     for item in filter {
+        let item = normalize_address(&item);
         if data.ends_with(item.as_str()) {
             if data != item {
                 res = Ok(());
@@ -303,31 +477,53 @@ mod tests {
         let config = parse_config("test/valid.json").unwrap();
 
         let recipients = "alen@example.com";
-        let (cc, to) = parse_recipients(&config, recipients);
+        let (bcc, cc, to) = parse_recipients(&config, recipients);
+        assert!(bcc.is_empty());
         assert!(cc.is_empty());
         assert_eq!(to.len(), 1);
         assert_eq!(to[0], "alen@example.com");
 
         let recipients = "alen@example.com,cc:,cc:bob@example.com,";
-        let (cc, to) = parse_recipients(&config, recipients);
+        let (bcc, cc, to) = parse_recipients(&config, recipients);
+        assert!(bcc.is_empty());
         assert_eq!(cc.len(), 1);
         assert_eq!(cc[0], "bob@example.com");
         assert_eq!(to.len(), 1);
         assert_eq!(to[0], "alen@example.com");
 
         let recipients = "alen@example.com,alen@example.com,cc:bob@example.com,cc:bob@example.com,";
-        let (cc, to) = parse_recipients(&config, recipients);
+        let (bcc, cc, to) = parse_recipients(&config, recipients);
+        assert!(bcc.is_empty());
         assert_eq!(cc.len(), 1);
         assert_eq!(cc[0], "bob@example.com");
         assert_eq!(to.len(), 1);
         assert_eq!(to[0], "alen@example.com");
 
         let recipients = "alen@example.com,bob@example.com,cc:bob@example.com,cc:bob@example.com,";
-        let (cc, to) = parse_recipients(&config, recipients);
+        let (bcc, cc, to) = parse_recipients(&config, recipients);
+        assert!(bcc.is_empty());
         assert!(cc.is_empty());
         assert_eq!(to.len(), 2);
         assert_eq!(to[0], "alen@example.com");
         assert_eq!(to[1], "bob@example.com");
+
+        let recipients =
+            "alen,bcc:,bcc:alen,cc:bob@example.com,bcc:bob@example.com,bcc:catherine,bcc:catherine";
+        let (bcc, cc, to) = parse_recipients(&config, recipients);
+        assert_eq!(bcc.len(), 1);
+        assert_eq!(bcc[0], "catherine");
+        assert_eq!(cc.len(), 1);
+        assert_eq!(cc[0], "bob@example.com");
+        assert_eq!(to.len(), 1);
+        assert_eq!(to[0], "alen");
+
+        let recipients = "\"Smith, Alen\" <alen@example.com>,cc:Bob <bob@example.com>,cc:bob@example.com,bcc:alen@example.com";
+        let (bcc, cc, to) = parse_recipients(&config, recipients);
+        assert!(bcc.is_empty());
+        assert_eq!(cc.len(), 1);
+        assert_eq!(cc[0], "Bob <bob@example.com>");
+        assert_eq!(to.len(), 1);
+        assert_eq!(to[0], "\"Smith, Alen\" <alen@example.com>");
     }
 
     #[test]
@@ -339,15 +535,89 @@ mod tests {
     fn test_print_address() {
         let filter = vec!["@example.com".to_string()];
 
+        let bcc = vec!["catherine@example.com".to_string()];
         let cc = vec!["alen@example.com".to_string()];
         let to = vec!["bob@example.com".to_string()];
-        print_address(cc.clone(), to.clone(), filter.clone());
+        print_address(bcc.clone(), cc.clone(), to.clone(), filter.clone(), vec![]);
 
         let to = vec![];
-        print_address(cc.clone(), to.clone(), filter.clone());
+        print_address(bcc.clone(), cc.clone(), to.clone(), filter.clone(), vec![]);
 
         let cc = vec![];
-        print_address(cc.clone(), to.clone(), filter.clone());
+        print_address(bcc.clone(), cc.clone(), to.clone(), filter.clone(), vec![]);
+
+        let bcc = vec![];
+        print_address(bcc.clone(), cc.clone(), to.clone(), filter.clone(), vec![]);
+    }
+
+    #[test]
+    fn test_parse_suppression() {
+        assert!(parse_suppression("").unwrap().is_empty());
+        assert_eq!(
+            parse_suppression("test/suppression.txt").unwrap(),
+            vec![
+                "bob@example.com".to_string(),
+                "catherine@example.com".to_string()
+            ]
+        );
+        assert!(parse_suppression("test/missing.txt").is_err());
+    }
+
+    #[test]
+    fn test_remove_suppressed() {
+        let suppression = parse_suppression("test/suppression.txt").unwrap();
+        let data = vec![
+            "alen@example.com".to_string(),
+            "Bob <BOB@example.com>".to_string(),
+            "catherine@EXAMPLE.com".to_string(),
+        ];
+
+        let ret = remove_suppressed(data.clone(), &suppression);
+        assert_eq!(ret, vec!["alen@example.com".to_string()]);
+
+        let ret = remove_suppressed(data.clone(), &[]);
+        assert_eq!(ret, data);
+    }
+
+    #[test]
+    fn test_split_recipients() {
+        let buf = split_recipients("", ",");
+        assert_eq!(buf, vec!["".to_string()]);
+
+        let buf = split_recipients("alen, bob@example.com,", ",");
+        assert_eq!(buf, vec!["alen", "bob@example.com", ""]);
+
+        let buf = split_recipients("\"Smith, Alen\" <alen@example.com>;bob", ";");
+        assert_eq!(buf, vec!["\"Smith, Alen\" <alen@example.com>", "bob"]);
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("alen"), "alen");
+        assert_eq!(parse_address("Alen <alen@example.com>"), "alen@example.com");
+    }
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(parse_name("alen@example.com"), "");
+        assert_eq!(parse_name("Alen Smith <alen@example.com>"), "Alen Smith");
+        assert_eq!(
+            parse_name("\"Smith, \\\"Alen\\\"\" <alen@example.com>"),
+            "Smith, \"Alen\""
+        );
+    }
+
+    #[test]
+    fn test_format_mailbox() {
+        assert_eq!(format_mailbox("", "alen@example.com"), "alen@example.com");
+        assert_eq!(
+            format_mailbox("Alen Smith", "alen@example.com"),
+            "Alen Smith <alen@example.com>"
+        );
+        assert_eq!(
+            format_mailbox("Smith, \"Alen\"", "alen@example.com"),
+            "\"Smith, \\\"Alen\\\"\" <alen@example.com>"
+        );
     }
 
     #[test]
@@ -370,6 +640,20 @@ mod tests {
 
         buf = remove_duplicates(buf);
         assert!(!helper(buf));
+
+        let buf = vec![
+            "Alen <alen@example.com>".to_string(),
+            "alen@example.com".to_string(),
+        ];
+        let buf = remove_duplicates(buf);
+        assert_eq!(buf, vec!["Alen <alen@example.com>".to_string()]);
+
+        let buf = vec![
+            "Jörg <jorg@Bücher.de>".to_string(),
+            "jorg@xn--bcher-kva.de".to_string(),
+        ];
+        let buf = remove_duplicates(buf);
+        assert_eq!(buf, vec!["Jörg <jorg@Bücher.de>".to_string()]);
     }
 
     #[test]
@@ -393,6 +677,20 @@ mod tests {
         let buf = collect_difference(buf_a, buf_b);
         assert_eq!(buf.len(), 1);
         assert_eq!(buf[0], "bob@example.com");
+
+        let buf_a = vec!["alen@EXAMPLE.com".to_string()];
+        let buf_b = vec!["Alen <alen@example.COM>".to_string()];
+        let buf = collect_difference(buf_a, buf_b);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_normalize_address() {
+        let buf = normalize_address("Jörg <jorg@BÜCHER.de>");
+        assert_eq!(buf, "jorg@xn--bcher-kva.de");
+
+        let buf = normalize_address("@Example.COM");
+        assert_eq!(buf, "@example.com");
     }
 
     #[test]
@@ -404,5 +702,19 @@ mod tests {
 
         let address = "@example.com".to_string();
         assert!(filter_address(address, filter.clone()).is_err());
+
+        let address = "Alen <alen@example.com>".to_string();
+        assert!(filter_address(address, filter.clone()).is_ok());
+
+        let address = "alen@Example.COM".to_string();
+        assert!(filter_address(address, filter.clone()).is_ok());
+
+        let filter = vec!["@bücher.de".to_string()];
+
+        let address = "jorg@xn--bcher-kva.de".to_string();
+        assert!(filter_address(address, filter.clone()).is_ok());
+
+        let address = "Jörg <jorg@BÜCHER.de>".to_string();
+        assert!(filter_address(address, filter.clone()).is_ok());
     }
 }
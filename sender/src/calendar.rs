@@ -0,0 +1,417 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lettre::message::Mailbox;
+
+use crate::Event;
+
+pub const CANCEL_METHOD: &str = "CANCEL";
+pub const REQUEST_METHOD: &str = "REQUEST";
+
+const LINE_WIDTH: usize = 75;
+const PRODUCT_ID: &str = "-//craftslab//rsmail//EN";
+
+pub fn build_calendar(
+    event: &Event,
+    summary: &str,
+    description: &str,
+    organizer: &str,
+    to: &[String],
+    cc: &[String],
+) -> Result<String, Box<dyn Error>> {
+    let mut buf: Vec<String> = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        format!("PRODID:{}", PRODUCT_ID),
+        "VERSION:2.0".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("METHOD:{}", event.method),
+    ];
+
+    let start = parse_datetime(&event.start)?;
+    let end = parse_datetime(&event.end)?;
+
+    if let (Some(s), Some(e)) = (start, end) {
+        if e.0 < s.0 {
+            return Err("event end is before start".into());
+        }
+    }
+
+    // A single TZID is only emitted when both ends share one offset
+    let timezone = match (start, end) {
+        (Some(s), Some(e)) if !event.timezone.is_empty() && s.1 == e.1 => {
+            buf.push("BEGIN:VTIMEZONE".to_string());
+            buf.push(format!("TZID:{}", escape_text(&event.timezone)));
+            buf.push("BEGIN:STANDARD".to_string());
+            buf.push("DTSTART:19700101T000000".to_string());
+            buf.push(format!("TZOFFSETFROM:{}", format_offset(s.1)));
+            buf.push(format!("TZOFFSETTO:{}", format_offset(s.1)));
+            buf.push("END:STANDARD".to_string());
+            buf.push("END:VTIMEZONE".to_string());
+            event.timezone.as_str()
+        }
+        _ => "",
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    buf.push("BEGIN:VEVENT".to_string());
+    buf.push(format!("UID:{}", escape_text(&event.uid)));
+    buf.push(format!("DTSTAMP{}", format_datetime(now, 0, "")));
+    buf.push(format!("SEQUENCE:{}", event.sequence));
+
+    if event.method == CANCEL_METHOD {
+        buf.push("STATUS:CANCELLED".to_string());
+    } else {
+        buf.push("STATUS:CONFIRMED".to_string());
+    }
+
+    buf.push(format!("SUMMARY:{}", escape_text(summary)));
+
+    if let Some((time, offset)) = start {
+        buf.push(format!(
+            "DTSTART{}",
+            format_datetime(time, offset, timezone)
+        ));
+    }
+
+    if let Some((time, offset)) = end {
+        buf.push(format!("DTEND{}", format_datetime(time, offset, timezone)));
+    }
+
+    if !event.location.is_empty() {
+        buf.push(format!("LOCATION:{}", escape_text(&event.location)));
+    }
+
+    if !description.is_empty() {
+        buf.push(format!("DESCRIPTION:{}", escape_text(description)));
+    }
+
+    buf.push(format!("ORGANIZER{}", format_address(organizer)?));
+
+    for (list, role) in [(to, "REQ-PARTICIPANT"), (cc, "OPT-PARTICIPANT")] {
+        for item in list {
+            buf.push(format!(
+                "ATTENDEE;ROLE={};PARTSTAT=NEEDS-ACTION;RSVP=TRUE{}",
+                role,
+                format_address(item)?
+            ));
+        }
+    }
+
+    buf.push("END:VEVENT".to_string());
+    buf.push("END:VCALENDAR".to_string());
+
+    let lines: Vec<String> = buf.iter().map(|l| fold_line(l)).collect();
+
+    return Ok(lines.join("\r\n") + "\r\n");
+}
+
+fn format_address(data: &str) -> Result<String, Box<dyn Error>> {
+    let mailbox: Mailbox = data.parse()?;
+
+    match mailbox.name {
+        Some(name) if !name.is_empty() => Ok(format!(
+            ";CN=\"{}\":mailto:{}",
+            name.replace('"', ""),
+            mailbox.email
+        )),
+        _ => Ok(format!(":mailto:{}", mailbox.email)),
+    }
+}
+
+// Returns seconds since the epoch in UTC and the UTC offset in seconds
+fn parse_datetime(data: &str) -> Result<Option<(i64, i64)>, Box<dyn Error>> {
+    let data = data.trim();
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    let invalid = || format!("invalid datetime {} (2026-10-20T15:00+08:00)", data);
+
+    let (date, time) = data.split_once(['T', ' ']).ok_or_else(invalid)?;
+    let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(i) => time.split_at(i),
+        None => return Err(invalid().into()),
+    };
+
+    let number = |s: &str| s.parse::<i64>().map_err(|_| invalid());
+
+    let date: Vec<&str> = date.split('-').collect();
+    if date.len() != 3 {
+        return Err(invalid().into());
+    }
+    let (year, month, day) = (number(date[0])?, number(date[1])?, number(date[2])?);
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return Err(invalid().into());
+    }
+
+    let time: Vec<&str> = time.split(':').collect();
+    if time.len() < 2 || time.len() > 3 {
+        return Err(invalid().into());
+    }
+    let hour = number(time[0])?;
+    let minute = number(time[1])?;
+    let second = if time.len() == 3 { number(time[2])? } else { 0 };
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid().into());
+    }
+
+    let offset = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let digits = offset[1..].replace(':', "");
+            // Checked byte by byte so the slicing below stays on char boundaries
+            if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid().into());
+            }
+            sign * (number(&digits[..2])? * 3600 + number(&digits[2..])? * 60)
+        }
+    };
+
+    let local = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+
+    return Ok(Some((local - offset, offset)));
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn format_datetime(time: i64, offset: i64, timezone: &str) -> String {
+    if timezone.is_empty() {
+        let (year, month, day, hour, minute, second) = civil_from_seconds(time);
+        return format!(
+            ":{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+            year, month, day, hour, minute, second
+        );
+    }
+
+    let (year, month, day, hour, minute, second) = civil_from_seconds(time + offset);
+
+    return format!(
+        ";TZID={}:{:04}{:02}{:02}T{:02}{:02}{:02}",
+        timezone, year, month, day, hour, minute, second
+    );
+}
+
+fn format_offset(offset: i64) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+
+    return format!("{}{:02}{:02}", sign, offset / 3600, offset % 3600 / 60);
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    return era * 146097 + doe - 719468;
+}
+
+fn civil_from_seconds(time: i64) -> (i64, i64, i64, i64, i64, i64) {
+    let days = time.div_euclid(86400) + 719468;
+    let secs = time.rem_euclid(86400);
+
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return (year, month, day, secs / 3600, secs % 3600 / 60, secs % 60);
+}
+
+fn escape_text(data: &str) -> String {
+    return data
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n");
+}
+
+// Lines longer than 75 octets continue on the next line after a space (RFC 5545 section 3.1)
+fn fold_line(data: &str) -> String {
+    let mut buf = String::new();
+    let mut width = 0;
+
+    for c in data.chars() {
+        if width + c.len_utf8() > LINE_WIDTH {
+            buf.push_str("\r\n ");
+            width = 1;
+        }
+        buf.push(c);
+        width += c.len_utf8();
+    }
+
+    return buf;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_event(method: &str, timezone: &str) -> Event {
+        Event {
+            end: "2026-10-20T16:30+08:00".to_string(),
+            location: "Room 1, Building A".to_string(),
+            method: method.to_string(),
+            sequence: 0,
+            start: "2026-10-20T15:00+08:00".to_string(),
+            timezone: timezone.to_string(),
+            uid: "1234@example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_datetime() {
+        assert_eq!(parse_datetime("").unwrap(), None);
+        assert_eq!(parse_datetime("1970-01-01T00:00Z").unwrap(), Some((0, 0)));
+        assert_eq!(
+            parse_datetime("2026-10-20 15:00:30+08:00").unwrap(),
+            Some((1792479630, 28800))
+        );
+        assert_eq!(
+            parse_datetime("2026-10-20T15:00-0530").unwrap(),
+            Some((1792528200, -19800))
+        );
+
+        assert!(parse_datetime("2026-10-20T15:00").is_err());
+        assert!(parse_datetime("2026-13-20T15:00Z").is_err());
+        assert!(parse_datetime("2026-02-29T15:00Z").is_err());
+        assert!(parse_datetime("2026-04-31T15:00Z").is_err());
+        assert!(parse_datetime("1900-02-29T15:00Z").is_err());
+        assert!(parse_datetime("2028-02-29T15:00Z").is_ok());
+        assert!(parse_datetime("2000-02-29T15:00Z").is_ok());
+        assert!(parse_datetime("2026-10-20T25:00Z").is_err());
+        assert!(parse_datetime("tomorrow").is_err());
+        assert!(parse_datetime("2026-10-20T15:00+0é0").is_err());
+        assert!(parse_datetime("2026-10-20T15:00+-800").is_err());
+    }
+
+    #[test]
+    fn test_format_datetime() {
+        assert_eq!(format_datetime(1792479600, 28800, ""), ":20261020T070000Z");
+        assert_eq!(
+            format_datetime(1792479600, 28800, "Asia/Shanghai"),
+            ";TZID=Asia/Shanghai:20261020T150000"
+        );
+        assert_eq!(format_offset(28800), "+0800");
+        assert_eq!(format_offset(-19800), "-0530");
+    }
+
+    #[test]
+    fn test_fold_line() {
+        let line = "D".repeat(160);
+        let ret = fold_line(&line);
+        let lines: Vec<&str> = ret.split("\r\n").collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].len(), 75);
+        assert!(lines[1].starts_with(' '));
+        assert_eq!(ret.replace("\r\n ", ""), line);
+
+        let ret = fold_line(&"会".repeat(30));
+        assert!(ret.split("\r\n").all(|l| l.len() <= 75));
+    }
+
+    #[test]
+    fn test_build_calendar() {
+        let event = build_event(REQUEST_METHOD, "Asia/Shanghai");
+        let to = vec!["\"Smith, Alen\" <alen@example.com>".to_string()];
+        let cc = vec!["bob@example.com".to_string()];
+
+        let ret = build_calendar(
+            &event,
+            "Review",
+            "Agenda; notes",
+            "sender@example.com",
+            &to,
+            &cc,
+        )
+        .unwrap();
+        assert!(ret.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ret.ends_with("END:VCALENDAR\r\n"));
+        assert!(ret.contains("METHOD:REQUEST\r\n"));
+        assert!(ret.contains("TZID:Asia/Shanghai\r\n"));
+        assert!(ret.contains("TZOFFSETTO:+0800\r\n"));
+        assert!(ret.contains("UID:1234@example.com\r\n"));
+        assert!(ret.contains("STATUS:CONFIRMED\r\n"));
+        assert!(ret.contains("DTSTART;TZID=Asia/Shanghai:20261020T150000\r\n"));
+        assert!(ret.contains("DTEND;TZID=Asia/Shanghai:20261020T163000\r\n"));
+        assert!(ret.contains("LOCATION:Room 1\\, Building A\r\n"));
+        assert!(ret.contains("DESCRIPTION:Agenda\\; notes\r\n"));
+        assert!(ret.contains("ORGANIZER:mailto:sender@example.com\r\n"));
+        assert!(ret.split("\r\n").all(|l| l.len() <= 75));
+
+        let ret = ret.replace("\r\n ", "");
+        assert!(ret.contains(
+            "ATTENDEE;ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE;\
+             CN=\"Smith, Alen\":mailto:alen@example.com\r\n"
+        ));
+        assert!(ret.contains(
+            "ATTENDEE;ROLE=OPT-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE:mailto:bob@example.com\r\n"
+        ));
+
+        let event = build_event(REQUEST_METHOD, "");
+        let ret = build_calendar(&event, "Review", "", "sender@example.com", &to, &[]).unwrap();
+        assert!(!ret.contains("VTIMEZONE"));
+        assert!(ret.contains("DTSTART:20261020T070000Z\r\n"));
+        assert!(ret.contains("DTEND:20261020T083000Z\r\n"));
+
+        let event = Event {
+            end: "2026-10-20T14:00+08:00".to_string(),
+            ..build_event(REQUEST_METHOD, "")
+        };
+        assert!(build_calendar(&event, "Review", "", "sender@example.com", &to, &[]).is_err());
+    }
+
+    #[test]
+    fn test_build_calendar_cancel() {
+        let event = Event {
+            end: "".to_string(),
+            sequence: 1,
+            start: "".to_string(),
+            ..build_event(CANCEL_METHOD, "Asia/Shanghai")
+        };
+
+        let ret = build_calendar(
+            &event,
+            "Review",
+            "",
+            "sender@example.com",
+            &["alen@example.com".to_string()],
+            &[],
+        )
+        .unwrap();
+        assert!(ret.contains("METHOD:CANCEL\r\n"));
+        assert!(ret.contains("STATUS:CANCELLED\r\n"));
+        assert!(ret.contains("SEQUENCE:1\r\n"));
+        assert!(!ret.contains("DTSTART"));
+        assert!(!ret.contains("VTIMEZONE"));
+    }
+}
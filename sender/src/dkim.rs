@@ -0,0 +1,429 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::base64;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::{Signer, Verifier};
+
+use crate::mime::{encode_base64, normalize_newlines, split_headers, split_message};
+use crate::Dkim;
+
+const DEFAULT_CANONICALIZATION: &str = "relaxed/relaxed";
+const DEFAULT_HEADERS: [&str; 5] = ["From", "To", "Cc", "Subject", "Date"];
+const SIGNATURE_HEADER: &str = "DKIM-Signature";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Canonicalization {
+    Relaxed,
+    Simple,
+}
+
+pub fn sign(data: &[u8], config: &Dkim) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = PKey::private_key_from_pem(&fs::read(&config.key)?)?;
+    let algorithm = match key.id() {
+        Id::RSA => "rsa-sha256",
+        Id::ED25519 => "ed25519-sha256",
+        _ => return Err(format!("unsupported dkim key {}", config.key).into()),
+    };
+
+    let mut canonicalization = config.canonicalization.to_lowercase();
+    if canonicalization.is_empty() {
+        canonicalization = DEFAULT_CANONICALIZATION.to_string();
+    }
+    let (header_type, body_type) = parse_canonicalization(canonicalization.as_str())?;
+
+    let mut names: Vec<String> = config
+        .headers
+        .iter()
+        .map(|h| h.trim().to_string())
+        .collect();
+    if names.is_empty() {
+        names = DEFAULT_HEADERS.iter().map(|h| h.to_string()).collect();
+    }
+
+    let data = normalize_newlines(data);
+    let (head, body) = split_message(&data);
+    let headers = split_headers(std::str::from_utf8(head)?);
+
+    let body_hash = base64::encode_block(&hash(
+        MessageDigest::sha256(),
+        &canonicalize_body(body, body_type),
+    )?);
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    // b= stays empty while hashing and is appended afterwards (RFC 6376 section 3.7)
+    let signature = format!(
+        "{}: v=1; a={}; c={}; d={}; s={}; t={};\r\n h={};\r\n bh={};\r\n b=",
+        SIGNATURE_HEADER,
+        algorithm,
+        canonicalization,
+        config.domain,
+        config.selector,
+        timestamp,
+        names.join(":"),
+        body_hash
+    );
+
+    let buf = build_signed_headers(&headers, &names, &signature, header_type);
+    let sig = match key.id() {
+        Id::RSA => {
+            Signer::new(MessageDigest::sha256(), &key)?.sign_oneshot_to_vec(buf.as_bytes())?
+        }
+        _ => {
+            let digest = hash(MessageDigest::sha256(), buf.as_bytes())?;
+            Signer::new_without_digest(&key)?.sign_oneshot_to_vec(&digest)?
+        }
+    };
+
+    let sig = encode_base64(&sig).replace("\r\n", "\r\n ");
+
+    let mut ret = format!("{}{}\r\n", signature, sig).into_bytes();
+    ret.extend_from_slice(&data);
+
+    return Ok(ret);
+}
+
+pub fn verify(data: &[u8], key: &[u8]) -> Result<String, Box<dyn Error>> {
+    let data = normalize_newlines(data);
+    let (head, body) = split_message(&data);
+    let headers = split_headers(std::str::from_utf8(head)?);
+
+    let signature = match headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(SIGNATURE_HEADER))
+    {
+        Some((_, raw)) => raw.to_string(),
+        None => return Err("dkim signature not found".into()),
+    };
+
+    let tags = parse_tags(signature.split_once(':').unwrap_or_default().1);
+    let tag = |name: &str| match tags.iter().find(|(k, _)| k == name) {
+        Some((_, v)) => Ok(v.to_string()),
+        None => Err(format!("dkim tag {} not found", name)),
+    };
+
+    let (header_type, body_type) = match tag("c") {
+        Ok(c) => parse_canonicalization(c.as_str())?,
+        Err(_) => (Canonicalization::Simple, Canonicalization::Simple),
+    };
+
+    let mut body = canonicalize_body(body, body_type);
+    if let Ok(l) = tag("l") {
+        body.truncate(l.parse()?);
+    }
+
+    let body_hash = base64::encode_block(&hash(MessageDigest::sha256(), &body)?);
+    if body_hash != tag("bh")? {
+        return Err("dkim body hash mismatch".into());
+    }
+
+    let names: Vec<String> = tag("h")?.split(':').map(|h| h.to_string()).collect();
+    let buf = build_signed_headers(&headers, &names, &strip_signature(&signature), header_type);
+
+    let key = parse_public_key(key)?;
+    let sig = base64::decode_block(&tag("b")?)?;
+
+    let valid = match tag("a")?.as_str() {
+        "rsa-sha256" => {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+            verifier.update(buf.as_bytes())?;
+            verifier.verify(&sig)?
+        }
+        "ed25519-sha256" => {
+            let digest = hash(MessageDigest::sha256(), buf.as_bytes())?;
+            Verifier::new_without_digest(&key)?.verify_oneshot(&sig, &digest)?
+        }
+        a => return Err(format!("unsupported dkim algorithm {}", a).into()),
+    };
+
+    if !valid {
+        return Err("dkim signature mismatch".into());
+    }
+
+    return Ok(format!("d={}; s={}", tag("d")?, tag("s")?));
+}
+
+fn parse_canonicalization(
+    name: &str,
+) -> Result<(Canonicalization, Canonicalization), Box<dyn Error>> {
+    let parse = |s: &str| match s.trim().to_lowercase().as_str() {
+        "relaxed" => Ok(Canonicalization::Relaxed),
+        "simple" => Ok(Canonicalization::Simple),
+        _ => Err(format!("invalid canonicalization {}", name)),
+    };
+
+    // A missing body algorithm defaults to simple (RFC 6376 section 3.5)
+    let (header, body) = name.split_once('/').unwrap_or((name, "simple"));
+
+    return Ok((parse(header)?, parse(body)?));
+}
+
+fn build_signed_headers(
+    headers: &[(String, String)],
+    names: &[String],
+    signature: &str,
+    canonicalization: Canonicalization,
+) -> String {
+    let mut used = vec![false; headers.len()];
+    let mut buf = String::new();
+
+    // Each listed name takes the last unused instance (RFC 6376 section 5.4.2)
+    for name in names {
+        let found = headers
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(i, (n, _))| !used[*i] && n.eq_ignore_ascii_case(name.trim()));
+        if let Some((i, (_, raw))) = found {
+            used[i] = true;
+            buf.push_str(&canonicalize_header(raw, canonicalization));
+        }
+    }
+
+    let signature = canonicalize_header(signature, canonicalization);
+    buf.push_str(signature.trim_end_matches("\r\n"));
+
+    return buf;
+}
+
+fn parse_tags(value: &str) -> Vec<(String, String)> {
+    return value
+        .split(';')
+        .filter_map(|item| item.split_once('='))
+        .map(|(k, v)| {
+            let v: String = v.chars().filter(|c| !c.is_whitespace()).collect();
+            (k.trim().to_string(), v)
+        })
+        .collect();
+}
+
+fn strip_signature(raw: &str) -> String {
+    let (name, value) = raw.split_once(':').unwrap_or((raw, ""));
+
+    let buf: Vec<String> = value
+        .split(';')
+        .map(|item| match item.split_once('=') {
+            Some((k, _)) if k.trim() == "b" => format!("{}=", k),
+            _ => item.to_string(),
+        })
+        .collect();
+
+    return format!("{}:{}", name, buf.join(";"));
+}
+
+fn canonicalize_header(raw: &str, canonicalization: Canonicalization) -> String {
+    match canonicalization {
+        Canonicalization::Simple => format!("{}\r\n", raw),
+        Canonicalization::Relaxed => {
+            let (name, value) = raw.split_once(':').unwrap_or((raw, ""));
+            let value = value.replace("\r\n", "");
+            let value: Vec<&str> = value.split([' ', '\t']).filter(|s| !s.is_empty()).collect();
+            format!("{}:{}\r\n", name.trim().to_lowercase(), value.join(" "))
+        }
+    }
+}
+
+fn canonicalize_body(data: &[u8], canonicalization: Canonicalization) -> Vec<u8> {
+    let mut buf: Vec<u8> = match canonicalization {
+        Canonicalization::Simple => data.to_vec(),
+        Canonicalization::Relaxed => {
+            let mut buf: Vec<u8> = Vec::with_capacity(data.len());
+            for line in data.split_inclusive(|c| *c == b'\n') {
+                let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+                let end = line
+                    .iter()
+                    .rposition(|c| *c != b' ' && *c != b'\t')
+                    .map_or(0, |i| i + 1);
+                for (i, c) in line[..end].iter().enumerate() {
+                    let space = *c == b' ' || *c == b'\t';
+                    if !space {
+                        buf.push(*c);
+                    } else if i == 0 || (line[i - 1] != b' ' && line[i - 1] != b'\t') {
+                        buf.push(b' ');
+                    }
+                }
+                buf.extend_from_slice(b"\r\n");
+            }
+            buf
+        }
+    };
+
+    while buf.ends_with(b"\r\n\r\n") {
+        buf.truncate(buf.len() - 2);
+    }
+
+    if !buf.is_empty() && !buf.ends_with(b"\r\n") {
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    // An empty body is a single CRLF under simple (RFC 6376 section 3.4.3), while
+    // a body of only empty lines is empty under relaxed (section 3.4.4)
+    match canonicalization {
+        Canonicalization::Simple if buf.is_empty() => buf.extend_from_slice(b"\r\n"),
+        Canonicalization::Relaxed if buf == b"\r\n" => buf.clear(),
+        _ => {}
+    }
+
+    return buf;
+}
+
+fn parse_public_key(data: &[u8]) -> Result<PKey<Public>, Box<dyn Error>> {
+    if data.starts_with(b"-----BEGIN") {
+        return PKey::public_key_from_pem(data).map_err(|e| e.into());
+    }
+
+    // DNS TXT record (v=DKIM1; k=rsa; p=...)
+    let tags = parse_tags(std::str::from_utf8(data)?);
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    };
+
+    let raw = base64::decode_block(tag("p").unwrap_or_default())?;
+    if raw.is_empty() {
+        return Err("dkim public key not found".into());
+    }
+
+    match tag("k").unwrap_or("rsa") {
+        "rsa" => PKey::public_key_from_der(&raw).map_err(|e| e.into()),
+        "ed25519" => PKey::public_key_from_raw_bytes(&raw, Id::ED25519).map_err(|e| e.into()),
+        k => Err(format!("unsupported dkim key type {}", k).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+    use std::env;
+
+    fn write_key(name: &str, key: &PKey<Private>) -> String {
+        let path = env::temp_dir().join(format!("sender-dkim-{}-{}", std::process::id(), name));
+        fs::write(&path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_canonicalization() {
+        let ret = parse_canonicalization("relaxed/simple").unwrap();
+        assert_eq!(ret, (Canonicalization::Relaxed, Canonicalization::Simple));
+
+        let ret = parse_canonicalization("Relaxed").unwrap();
+        assert_eq!(ret, (Canonicalization::Relaxed, Canonicalization::Simple));
+
+        assert!(parse_canonicalization("strict/simple").is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_body() {
+        let data = b"Hello  \t Bob, \r\n\r\nbye\t\r\n\r\n\r\n";
+
+        let ret = canonicalize_body(data, Canonicalization::Relaxed);
+        assert_eq!(ret, b"Hello Bob,\r\n\r\nbye\r\n");
+
+        let ret = canonicalize_body(data, Canonicalization::Simple);
+        assert_eq!(ret, b"Hello  \t Bob, \r\n\r\nbye\t\r\n");
+
+        assert_eq!(canonicalize_body(b"", Canonicalization::Relaxed), b"");
+        assert_eq!(canonicalize_body(b"", Canonicalization::Simple), b"\r\n");
+        assert_eq!(canonicalize_body(b"\r\n", Canonicalization::Relaxed), b"");
+        assert_eq!(
+            canonicalize_body(b" \t\r\n\r\n", Canonicalization::Relaxed),
+            b""
+        );
+        assert_eq!(
+            canonicalize_body(b"\r\n\r\n", Canonicalization::Simple),
+            b"\r\n"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_header() {
+        let raw = "Subject :  Build\r\n \t42  ";
+
+        let ret = canonicalize_header(raw, Canonicalization::Relaxed);
+        assert_eq!(ret, "subject:Build 42\r\n");
+
+        let ret = canonicalize_header(raw, Canonicalization::Simple);
+        assert_eq!(ret, "Subject :  Build\r\n \t42  \r\n");
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let data = "From: Alen Smith <alen@example.com>\r\n\
+                    To: bob@example.com\r\n\
+                    Subject: Build   42 passed\r\n\
+                    \r\n\
+                    Hello  Bob,\r\n\r\nall tests passed.  \r\n\r\n";
+
+        let rsa = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ed25519 = PKey::generate_ed25519().unwrap();
+
+        for (name, key) in [("rsa.pem", rsa), ("ed25519.pem", ed25519)] {
+            let path = write_key(name, &key);
+            let public = key.public_key_to_pem().unwrap();
+
+            for canonicalization in ["", "relaxed/simple", "simple/relaxed", "simple/simple"] {
+                let config = Dkim {
+                    canonicalization: canonicalization.to_string(),
+                    domain: "example.com".to_string(),
+                    headers: vec![],
+                    key: path.to_string(),
+                    selector: "mail".to_string(),
+                };
+
+                let signed = sign(data.as_bytes(), &config).unwrap();
+                let ret = verify(&signed, &public).unwrap();
+                assert_eq!(ret, "d=example.com; s=mail");
+
+                let text = String::from_utf8(signed).unwrap();
+                assert!(verify(text.replace("\r\n", "\n").as_bytes(), &public).is_ok());
+                assert!(verify(text.replace("Bob,", "Eve,").as_bytes(), &public).is_err());
+                assert!(verify(text.replace("Build", "Fail").as_bytes(), &public).is_err());
+            }
+
+            fs::remove_file(&path).unwrap();
+        }
+
+        assert!(verify(data.as_bytes(), b"").is_err());
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let key = PKey::generate_ed25519().unwrap();
+        let record = format!(
+            "v=DKIM1; k=ed25519; p={}",
+            base64::encode_block(&key.raw_public_key().unwrap())
+        );
+        let ret = parse_public_key(record.as_bytes()).unwrap();
+        assert_eq!(ret.id(), Id::ED25519);
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let record = format!(
+            "v=DKIM1; p={}",
+            base64::encode_block(&key.public_key_to_der().unwrap())
+        );
+        let ret = parse_public_key(record.as_bytes()).unwrap();
+        assert_eq!(ret.id(), Id::RSA);
+
+        assert!(parse_public_key(b"v=DKIM1; p=").is_err());
+    }
+}
@@ -0,0 +1,436 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use lettre::address::{Address, Envelope};
+
+const CONNECTION_CODE: u16 = 421;
+const DEFAULT_PORT: u16 = 24;
+const LMTP_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub address: String,
+    pub name: String,
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub code: u16,
+    pub recipient: String,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub struct DeliveryError {
+    pub failures: Vec<Failure>,
+    pub total: usize,
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let buf: Vec<String> = self
+            .failures
+            .iter()
+            .map(|e| format!("{}: {} {}", e.recipient, e.code, e.text))
+            .collect();
+
+        return write!(
+            f,
+            "lmtp delivery failed for {} of {} recipients: {}",
+            self.failures.len(),
+            self.total,
+            buf.join("; ")
+        );
+    }
+}
+
+impl Error for DeliveryError {}
+
+// Worth another attempt when any failed recipient got a 4xx reply, since the
+// rejected ones are dropped from the envelope before retrying
+pub fn is_transient(err: &DeliveryError) -> bool {
+    return err.failures.iter().any(|e| (400..500).contains(&e.code));
+}
+
+pub fn rejected(err: &DeliveryError) -> Vec<&Failure> {
+    return err
+        .failures
+        .iter()
+        .filter(|e| !(400..500).contains(&e.code))
+        .collect();
+}
+
+// Recipients that were delivered or rejected must not get the message again on a
+// later attempt
+pub fn remaining(err: &DeliveryError, envelope: &Envelope) -> Result<Envelope, Box<dyn Error>> {
+    let mut to = Vec::new();
+    for item in err.failures.iter().filter(|e| (400..500).contains(&e.code)) {
+        to.push(item.recipient.parse::<Address>()?);
+    }
+
+    return Ok(Envelope::new(envelope.from().cloned(), to)?);
+}
+
+pub fn send(client: &Client, envelope: &Envelope, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let from = envelope.from().map_or("".to_string(), |a| a.to_string());
+    let recipients: Vec<String> = envelope.to().iter().map(|a| a.to_string()).collect();
+    let mut replies = Vec::new();
+
+    let path = client
+        .address
+        .strip_prefix("unix:")
+        .or(Some(client.address.as_str()).filter(|a| a.starts_with('/')));
+
+    let ret = match path {
+        #[cfg(unix)]
+        Some(p) => UnixStream::connect(p).and_then(|s| {
+            s.set_read_timeout(Some(LMTP_TIMEOUT))?;
+            s.set_write_timeout(Some(LMTP_TIMEOUT))?;
+            session(s, client, &from, &recipients, data, &mut replies)
+        }),
+        #[cfg(not(unix))]
+        Some(_) => return Err("unix sockets are not supported on this platform".into()),
+        None => connect(&client.address).and_then(|s| {
+            s.set_read_timeout(Some(LMTP_TIMEOUT))?;
+            s.set_write_timeout(Some(LMTP_TIMEOUT))?;
+            session(s, client, &from, &recipients, data, &mut replies)
+        }),
+    };
+
+    // A dropped session leaves the unanswered recipients undelivered
+    if let Err(e) = ret {
+        for item in &recipients {
+            if !replies.iter().any(|r: &Failure| &r.recipient == item) {
+                replies.push(Failure {
+                    code: CONNECTION_CODE,
+                    recipient: item.to_string(),
+                    text: format!("connection error: {}", e),
+                });
+            }
+        }
+    }
+
+    let failures: Vec<Failure> = replies
+        .into_iter()
+        .filter(|e| !(200..300).contains(&e.code))
+        .collect();
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    return Err(DeliveryError {
+        failures,
+        total: recipients.len(),
+    }
+    .into());
+}
+
+fn connect(address: &str) -> io::Result<TcpStream> {
+    match address.rsplit_once(':') {
+        Some((h, p)) if !address.ends_with(']') => match p.parse::<u16>() {
+            Ok(p) => TcpStream::connect((h.trim_matches(['[', ']']), p)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        },
+        _ => TcpStream::connect((address.trim_matches(['[', ']']), DEFAULT_PORT)),
+    }
+}
+
+fn session<S: Read + Write>(
+    stream: S,
+    client: &Client,
+    from: &str,
+    recipients: &[String],
+    data: &[u8],
+    replies: &mut Vec<Failure>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    let mut reply = read_reply(&mut reader)?;
+    if reply.0 == 220 {
+        reply = command(&mut reader, &format!("LHLO {}", client.name))?;
+    }
+    if reply.0 == 250 {
+        reply = command(&mut reader, &format!("MAIL FROM:<{}>", from))?;
+    }
+
+    if reply.0 != 250 {
+        for item in recipients {
+            replies.push(build_reply(item, &reply));
+        }
+        let _ = command(&mut reader, "QUIT");
+        return Ok(());
+    }
+
+    let mut accepted = Vec::new();
+    for item in recipients {
+        let reply = command(&mut reader, &format!("RCPT TO:<{}>", item))?;
+        if reply.0 == 250 || reply.0 == 251 {
+            accepted.push(item);
+        } else {
+            replies.push(build_reply(item, &reply));
+        }
+    }
+
+    if !accepted.is_empty() {
+        let reply = command(&mut reader, "DATA")?;
+        if reply.0 == 354 {
+            reader.get_mut().write_all(&stuff_data(data))?;
+            reader.get_mut().write_all(b".\r\n")?;
+            reader.get_mut().flush()?;
+
+            // The final dot gets one reply per accepted recipient in RCPT order (RFC 2033 section 4.2)
+            for item in accepted {
+                let reply = read_reply(&mut reader)?;
+                replies.push(build_reply(item, &reply));
+            }
+        } else {
+            for item in accepted {
+                replies.push(build_reply(item, &reply));
+            }
+        }
+    }
+
+    let _ = command(&mut reader, "QUIT");
+
+    return Ok(());
+}
+
+fn build_reply(recipient: &str, reply: &(u16, String)) -> Failure {
+    return Failure {
+        code: reply.0,
+        recipient: recipient.to_string(),
+        text: reply.1.clone(),
+    };
+}
+
+fn command<S: Read + Write>(reader: &mut BufReader<S>, line: &str) -> io::Result<(u16, String)> {
+    reader
+        .get_mut()
+        .write_all(format!("{}\r\n", line).as_bytes())?;
+    reader.get_mut().flush()?;
+
+    return read_reply(reader);
+}
+
+fn read_reply<S: Read>(reader: &mut BufReader<S>) -> io::Result<(u16, String)> {
+    let mut buf = Vec::new();
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let line = line.trim_end();
+        let code = line
+            .get(..3)
+            .and_then(|c| c.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, line.to_string()))?;
+        buf.push(line.get(4..).unwrap_or_default().to_string());
+
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, buf.join(" ")));
+        }
+    }
+}
+
+// Lines starting with a dot get another one so the server does not take them
+// as the end of data, and the data always ends with a line break
+fn stuff_data(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 2);
+    let mut start = true;
+
+    for b in data {
+        if start && *b == b'.' {
+            buf.push(b'.');
+        }
+        buf.push(*b);
+        start = *b == b'\n';
+    }
+
+    if !buf.ends_with(b"\r\n") {
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    return buf;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Rejects bob at RCPT and defers catherine after DATA
+    fn serve_lmtp<S: Read + Write>(stream: S, log: Arc<Mutex<Vec<String>>>) {
+        let mut reader = BufReader::new(stream);
+        let mut rcpt = Vec::new();
+        let mut data = false;
+        reader
+            .get_mut()
+            .write_all(b"220 localhost LMTP\r\n")
+            .unwrap();
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            log.lock().unwrap().push(line.clone());
+
+            let reply = if data {
+                if line != ".\r\n" {
+                    continue;
+                }
+                data = false;
+                rcpt.iter()
+                    .map(|r: &String| match r.contains("catherine") {
+                        true => format!("452 4.2.2 {} over quota\r\n", r),
+                        false => format!("250 2.1.5 {} delivered\r\n", r),
+                    })
+                    .collect::<String>()
+            } else if line.starts_with("LHLO") {
+                "250-localhost\r\n250-PIPELINING\r\n250 ENHANCEDSTATUSCODES\r\n".to_string()
+            } else if line.starts_with("RCPT") && line.contains("bob") {
+                "550 5.1.1 User unknown\r\n".to_string()
+            } else if line.starts_with("RCPT") {
+                rcpt.push(line[9..line.len() - 3].to_string());
+                "250 2.1.5 OK\r\n".to_string()
+            } else if line.starts_with("DATA") {
+                data = true;
+                "354 Start mail input\r\n".to_string()
+            } else if line.starts_with("QUIT") {
+                reader.get_mut().write_all(b"221 Bye\r\n").unwrap();
+                break;
+            } else {
+                "250 OK\r\n".to_string()
+            };
+
+            reader.get_mut().write_all(reply.as_bytes()).unwrap();
+        }
+    }
+
+    fn build_envelope(to: &[&str]) -> Envelope {
+        Envelope::new(
+            Some("sender@example.com".parse().unwrap()),
+            to.iter().map(|a| a.parse().unwrap()).collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_send() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Client {
+            address: listener.local_addr().unwrap().to_string(),
+            name: "example.com".to_string(),
+        };
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let buf = log.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                serve_lmtp(stream.unwrap(), buf.clone());
+            }
+        });
+
+        let ret = send(
+            &client,
+            &build_envelope(&["alen@example.com"]),
+            b"Subject: Build 42\r\n\r\n.hidden\r\nbody",
+        );
+        assert!(ret.is_ok());
+        let log = log.lock().unwrap().clone();
+        assert_eq!(log[0], "LHLO example.com\r\n");
+        assert_eq!(log[1], "MAIL FROM:<sender@example.com>\r\n");
+        assert!(log.contains(&"..hidden\r\n".to_string()));
+        assert!(log.contains(&"body\r\n".to_string()));
+
+        let envelope = build_envelope(&[
+            "alen@example.com",
+            "bob@example.com",
+            "catherine@example.com",
+        ]);
+        let err = send(&client, &envelope, b"body\r\n").unwrap_err();
+        let err = err.downcast_ref::<DeliveryError>().unwrap();
+        assert_eq!(err.total, 3);
+        assert_eq!(err.failures.len(), 2);
+        assert_eq!(err.failures[0].recipient, "bob@example.com");
+        assert_eq!(err.failures[0].code, 550);
+        assert_eq!(err.failures[1].recipient, "catherine@example.com");
+        assert_eq!(err.failures[1].code, 452);
+        assert_eq!(
+            err.to_string(),
+            "lmtp delivery failed for 2 of 3 recipients: \
+             bob@example.com: 550 5.1.1 User unknown; \
+             catherine@example.com: 452 4.2.2 catherine@example.com over quota"
+        );
+        assert!(is_transient(err));
+        assert_eq!(rejected(err).len(), 1);
+        assert_eq!(rejected(err)[0].recipient, "bob@example.com");
+
+        let ret = remaining(err, &envelope).unwrap();
+        assert_eq!(ret.from(), envelope.from());
+        assert_eq!(ret.to().len(), 1);
+        assert_eq!(ret.to()[0].to_string(), "catherine@example.com");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_unix() {
+        let name = env::temp_dir().join(format!(
+            "sender-lmtp-{}.sock",
+            &crate::mime::build_boundary().unwrap()[..16]
+        ));
+        let listener = UnixListener::bind(&name).unwrap();
+        let client = Client {
+            address: format!("unix:{}", name.display()),
+            name: "example.com".to_string(),
+        };
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_lmtp(stream, Arc::new(Mutex::new(Vec::new())));
+        });
+
+        let envelope = build_envelope(&["alen@example.com", "catherine@example.com"]);
+        let err = send(&client, &envelope, b"body\r\n").unwrap_err();
+        let err = err.downcast_ref::<DeliveryError>().unwrap();
+        assert_eq!(err.failures.len(), 1);
+        assert!(is_transient(err));
+
+        fs::remove_file(&name).unwrap();
+
+        let err = send(&client, &envelope, b"body\r\n").unwrap_err();
+        let err = err.downcast_ref::<DeliveryError>().unwrap();
+        assert_eq!(err.failures.len(), 2);
+        assert_eq!(err.failures[0].code, CONNECTION_CODE);
+        assert!(is_transient(err));
+    }
+
+    #[test]
+    fn test_stuff_data() {
+        assert_eq!(stuff_data(b".a\r\nb\r\n.\r\n"), b"..a\r\nb\r\n..\r\n");
+        assert_eq!(stuff_data(b"a"), b"a\r\n");
+    }
+}
@@ -0,0 +1,110 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lettre::address::Envelope;
+
+use crate::mime::build_boundary;
+
+const CUR_DIR: &str = "cur";
+const NEW_DIR: &str = "new";
+const TMP_DIR: &str = "tmp";
+
+pub fn deliver(dir: &str, envelope: &Envelope, data: &[u8]) -> Result<String, Box<dyn Error>> {
+    for item in [CUR_DIR, NEW_DIR, TMP_DIR] {
+        fs::create_dir_all(Path::new(dir).join(item))?;
+    }
+
+    let name = build_name()?;
+    let tmp = Path::new(dir).join(TMP_DIR).join(&name);
+    let new = Path::new(dir).join(NEW_DIR).join(&name);
+
+    // Local delivery records the envelope sender and stores lines with bare LF
+    let mut buf = match envelope.from() {
+        Some(f) => format!("Return-Path: <{}>\n", f).into_bytes(),
+        None => b"Return-Path: <>\n".to_vec(),
+    };
+
+    let mut iter = data.iter().peekable();
+    while let Some(b) = iter.next() {
+        if *b == b'\r' && iter.peek() == Some(&&b'\n') {
+            continue;
+        }
+        buf.push(*b);
+    }
+
+    // Readers only scan new/ so the message shows up once fully written (maildir(5))
+    fs::write(&tmp, &buf)?;
+    fs::rename(&tmp, &new)?;
+
+    return Ok(new.to_string_lossy().to_string());
+}
+
+fn build_name() -> Result<String, Box<dyn Error>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let host = env::var("HOSTNAME").unwrap_or("localhost".to_string());
+
+    return Ok(format!(
+        "{}.M{}P{}R{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        process::id(),
+        &build_boundary()?[..16],
+        host.replace('/', "\\057").replace(':', "\\072")
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deliver() {
+        let dir = env::temp_dir().join(format!(
+            "sender-maildir-{}",
+            &build_boundary().unwrap()[..16]
+        ));
+        let dir = dir.to_str().unwrap();
+        let envelope = Envelope::new(
+            Some("sender@example.com".parse().unwrap()),
+            vec!["alen@example.com".parse().unwrap()],
+        )
+        .unwrap();
+
+        let name = deliver(dir, &envelope, b"Subject: Build 42\r\n\r\nbody\r\n").unwrap();
+        assert!(name.contains("/new/"));
+        assert_eq!(
+            fs::read_to_string(&name).unwrap(),
+            "Return-Path: <sender@example.com>\nSubject: Build 42\n\nbody\n"
+        );
+        assert_eq!(
+            fs::read_dir(Path::new(dir).join(TMP_DIR)).unwrap().count(),
+            0
+        );
+        assert!(Path::new(dir).join(CUR_DIR).is_dir());
+
+        let ret = deliver(dir, &envelope, b"second").unwrap();
+        assert_ne!(ret, name);
+        assert_eq!(
+            fs::read_dir(Path::new(dir).join(NEW_DIR)).unwrap().count(),
+            2
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
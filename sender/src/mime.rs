@@ -0,0 +1,144 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+
+use openssl::base64;
+use openssl::rand::rand_bytes;
+
+const BASE64_WIDTH: usize = 76;
+
+pub fn normalize_newlines(data: &[u8]) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::with_capacity(data.len());
+
+    for (i, c) in data.iter().enumerate() {
+        if *c == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            buf.push(b'\r');
+        }
+        buf.push(*c);
+    }
+
+    return buf;
+}
+
+pub fn split_message(data: &[u8]) -> (&[u8], &[u8]) {
+    match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => (&data[..i + 2], &data[i + 4..]),
+        None => (data, &[]),
+    }
+}
+
+pub fn split_headers(data: &str) -> Vec<(String, String)> {
+    let mut buf: Vec<(String, String)> = Vec::new();
+
+    for line in data.split_terminator("\r\n") {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, raw)) = buf.last_mut() {
+                raw.push_str("\r\n");
+                raw.push_str(line);
+            }
+        } else if let Some((name, _)) = line.split_once(':') {
+            buf.push((name.trim().to_string(), line.to_string()));
+        }
+    }
+
+    return buf;
+}
+
+pub fn split_entity(data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let (head, body) = split_message(data);
+    let mut headers: Vec<u8> = Vec::new();
+    let mut entity: Vec<u8> = Vec::new();
+
+    // Content-* headers describe the body and move along with it
+    for (name, raw) in split_headers(std::str::from_utf8(head)?) {
+        let buf = if name.to_lowercase().starts_with("content-") {
+            &mut entity
+        } else {
+            &mut headers
+        };
+        buf.extend_from_slice(raw.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    entity.extend_from_slice(b"\r\n");
+    entity.extend_from_slice(body);
+
+    return Ok((headers, entity));
+}
+
+pub fn build_boundary() -> Result<String, Box<dyn Error>> {
+    let mut buf = [0u8; 16];
+    rand_bytes(&mut buf)?;
+
+    return Ok(buf.iter().map(|b| format!("{:02x}", b)).collect());
+}
+
+pub fn encode_base64(data: &[u8]) -> String {
+    let buf = base64::encode_block(data);
+
+    let lines: Vec<&str> = buf
+        .as_bytes()
+        .chunks(BASE64_WIDTH)
+        .map(|c| std::str::from_utf8(c).unwrap_or_default())
+        .collect();
+
+    return lines.join("\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_newlines() {
+        let ret = normalize_newlines(b"a\nb\r\nc\n");
+        assert_eq!(ret, b"a\r\nb\r\nc\r\n");
+    }
+
+    #[test]
+    fn test_split_headers() {
+        let ret = split_headers("Subject: Build\r\n 42\r\nTo: bob@example.com\r\n");
+        assert_eq!(ret.len(), 2);
+        assert_eq!(
+            ret[0],
+            ("Subject".to_string(), "Subject: Build\r\n 42".to_string())
+        );
+        assert_eq!(ret[1].0, "To");
+    }
+
+    #[test]
+    fn test_split_entity() {
+        let data = b"From: alen@example.com\r\n\
+                     Content-Type: text/plain;\r\n charset=utf-8\r\n\
+                     MIME-Version: 1.0\r\n\
+                     Content-Transfer-Encoding: 7bit\r\n\
+                     \r\n\
+                     Hello\r\n";
+
+        let (headers, entity) = split_entity(data).unwrap();
+        assert_eq!(headers, b"From: alen@example.com\r\nMIME-Version: 1.0\r\n");
+        assert_eq!(
+            entity,
+            b"Content-Type: text/plain;\r\n charset=utf-8\r\n\
+              Content-Transfer-Encoding: 7bit\r\n\r\nHello\r\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        let ret = encode_base64(&[0u8; 60]);
+        let lines: Vec<&str> = ret.split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), 76);
+    }
+}
@@ -0,0 +1,318 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+
+use openssl::ssl::{SslConnector, SslMethod};
+use serde_json::Value;
+
+use crate::Auth;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn fetch_token(auth: &Auth, ca: &str) -> Result<String, Box<dyn Error>> {
+    let mut form: Vec<(&str, &str)> = Vec::new();
+
+    // Without a refresh token the client acts on its own behalf (RFC 6749 section 4.4)
+    if auth.refresh_token.is_empty() {
+        form.push(("grant_type", "client_credentials"));
+    } else {
+        form.push(("grant_type", "refresh_token"));
+        form.push(("refresh_token", &auth.refresh_token));
+    }
+
+    form.push(("client_id", &auth.client_id));
+
+    if !auth.client_secret.is_empty() {
+        form.push(("client_secret", &auth.client_secret));
+    }
+
+    if !auth.scope.is_empty() {
+        form.push(("scope", &auth.scope));
+    }
+
+    let body: Vec<String> = form
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, encode_form(v)))
+        .collect();
+
+    let (status, data) = post_form(&auth.token_url, &body.join("&"), ca)?;
+    let data: Value = serde_json::from_slice(&data)
+        .map_err(|e| format!("token response invalid ({}): {}", status, e))?;
+
+    if !(200..300).contains(&status) {
+        let error = data["error"].as_str().unwrap_or("unknown error");
+        let description = data["error_description"].as_str().unwrap_or_default();
+        return Err(format!(
+            "token request failed ({}): {} {}",
+            status, error, description
+        )
+        .trim_end()
+        .into());
+    }
+
+    match data["access_token"].as_str() {
+        Some(t) if !t.is_empty() => Ok(t.to_string()),
+        _ => Err("token response has no access_token".into()),
+    }
+}
+
+fn post_form(url: &str, body: &str, ca: &str) -> Result<(u16, Vec<u8>), Box<dyn Error>> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| format!("token url invalid: {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+
+    let default_port = match scheme {
+        "http" => 80,
+        "https" => 443,
+        _ => return Err(format!("token url invalid: {}", url).into()),
+    };
+
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((h, p)) if !authority.ends_with(']') => (h, p.parse::<u16>()?),
+        _ => (authority, default_port),
+    };
+
+    // HTTP/1.0 keeps the response free of chunked transfer coding
+    let request = format!(
+        "POST {} HTTP/1.0\r\n\
+         Host: {}\r\n\
+         Accept: application/json\r\n\
+         Content-Type: application/x-www-form-urlencoded\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        path,
+        authority,
+        body.len(),
+        body
+    );
+
+    let stream = TcpStream::connect((host.trim_matches(['[', ']']), port))?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    let mut buf = Vec::new();
+
+    if scheme == "https" {
+        let connector = build_connector(ca)?;
+        let mut stream = connector.connect(host.trim_matches(['[', ']']), stream)?;
+        stream.write_all(request.as_bytes())?;
+        stream.read_to_end(&mut buf)?;
+    } else {
+        let mut stream = stream;
+        stream.write_all(request.as_bytes())?;
+        stream.read_to_end(&mut buf)?;
+    }
+
+    return parse_response(&buf);
+}
+
+// Vendored OpenSSL only looks in its build-time certificate directory, so trust
+// the host's bundle as found by openssl-probe plus the configured CA file
+fn build_connector(ca: &str) -> Result<SslConnector, Box<dyn Error>> {
+    let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+    let probe = openssl_probe::probe();
+
+    if let Some(f) = &probe.cert_file {
+        builder.load_verify_locations(Some(f), None)?;
+    }
+    for item in &probe.cert_dir {
+        builder.load_verify_locations(None, Some(item))?;
+    }
+    if !ca.is_empty() {
+        builder.load_verify_locations(Some(Path::new(ca)), None)?;
+    }
+
+    return Ok(builder.build());
+}
+
+fn parse_response(data: &[u8]) -> Result<(u16, Vec<u8>), Box<dyn Error>> {
+    let (head, body) = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => (&data[..i], &data[i + 4..]),
+        None => return Err("token response invalid".into()),
+    };
+
+    let head = String::from_utf8_lossy(head);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or("token response invalid")?;
+
+    return Ok((status, body.to_vec()));
+}
+
+fn encode_form(data: &str) -> String {
+    let mut buf = String::new();
+
+    for b in data.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                buf.push(b as char)
+            }
+            b' ' => buf.push('+'),
+            _ => buf.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    return buf;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn start_token_server(
+        status: &'static str,
+        body: &'static str,
+    ) -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(l) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = l.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut content = vec![0u8; length];
+            reader.read_exact(&mut content).unwrap();
+            request.push_str(&String::from_utf8(content).unwrap());
+            sender.send(request).unwrap();
+
+            let response = format!(
+                "HTTP/1.0 {}\r\nContent-Type: application/json\r\n\r\n{}",
+                status, body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        (port, receiver)
+    }
+
+    fn build_auth(port: u16, refresh_token: &str) -> Auth {
+        Auth {
+            client_id: "rsmail".to_string(),
+            client_secret: "s3cret&=".to_string(),
+            mechanism: "XOAUTH2".to_string(),
+            refresh_token: refresh_token.to_string(),
+            scope: "".to_string(),
+            token_url: format!("http://127.0.0.1:{}/oauth2/token", port),
+        }
+    }
+
+    #[test]
+    fn test_fetch_token() {
+        let (port, receiver) = start_token_server(
+            "200 OK",
+            r#"{"access_token":"ya29.token","expires_in":3599,"token_type":"Bearer"}"#,
+        );
+        let ret = fetch_token(&build_auth(port, "1//refresh"), "");
+        assert_eq!(ret.unwrap(), "ya29.token");
+
+        let request = receiver.recv().unwrap();
+        assert!(request.starts_with("POST /oauth2/token HTTP/1.0\r\n"));
+        assert!(request.contains("Content-Type: application/x-www-form-urlencoded\r\n"));
+        assert!(request.ends_with(
+            "grant_type=refresh_token&refresh_token=1%2F%2Frefresh\
+             &client_id=rsmail&client_secret=s3cret%26%3D"
+        ));
+
+        let (port, receiver) = start_token_server(
+            "400 Bad Request",
+            r#"{"error":"invalid_grant","error_description":"Token has been revoked."}"#,
+        );
+        let ret = fetch_token(&build_auth(port, ""), "");
+        assert_eq!(
+            ret.unwrap_err().to_string(),
+            "token request failed (400): invalid_grant Token has been revoked."
+        );
+        assert!(receiver
+            .recv()
+            .unwrap()
+            .contains("grant_type=client_credentials&client_id=rsmail"));
+
+        let (port, _) = start_token_server("200 OK", r#"{"token_type":"Bearer"}"#);
+        assert!(fetch_token(&build_auth(port, "1//refresh"), "").is_err());
+    }
+
+    #[test]
+    fn test_fetch_token_https() {
+        use openssl::ssl::{SslAcceptor, SslFiletype};
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_certificate_file("test/localhost.crt", SslFiletype::PEM)
+            .unwrap();
+        acceptor
+            .set_private_key_file("test/localhost.key", SslFiletype::PEM)
+            .unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match acceptor.accept(stream.unwrap()) {
+                    Ok(s) => s,
+                    Err(_) => continue,
+                };
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).unwrap();
+                stream
+                    .write_all(b"HTTP/1.0 200 OK\r\n\r\n{\"access_token\":\"ya29.token\"}")
+                    .unwrap();
+            }
+        });
+
+        let mut auth = build_auth(port, "1//refresh");
+        auth.token_url = format!("https://127.0.0.1:{}/oauth2/token", port);
+
+        // The self-signed endpoint is only trusted through the CA file
+        assert!(fetch_token(&auth, "").is_err());
+        assert_eq!(
+            fetch_token(&auth, "test/localhost.crt").unwrap(),
+            "ya29.token"
+        );
+        assert!(fetch_token(&auth, "test/missing.crt").is_err());
+    }
+
+    #[test]
+    fn test_encode_form() {
+        assert_eq!(encode_form("a b/c=d&e~"), "a+b%2Fc%3Dd%26e~");
+        assert_eq!(encode_form("邮件"), "%E9%82%AE%E4%BB%B6");
+    }
+}
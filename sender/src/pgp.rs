@@ -0,0 +1,328 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::mime::{build_boundary, normalize_newlines, split_entity};
+use crate::Pgp;
+
+const GPG_BINARY: &str = "gpg";
+
+pub fn protect(
+    data: &[u8],
+    recipients: &[String],
+    config: &Pgp,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let data = normalize_newlines(data);
+    let (mut headers, entity) = split_entity(&data)?;
+
+    let mut keys: Vec<PathBuf> = Vec::new();
+
+    if !config.keyring.is_empty() {
+        for item in recipients {
+            let path = Path::new(&config.keyring).join(format!("{}.asc", item.to_lowercase()));
+            if path.is_file() {
+                keys.push(path);
+            } else if config.allow_unencrypted {
+                eprintln!("pgp key not found for {}, sending unencrypted", item);
+                keys.clear();
+                break;
+            } else {
+                return Err(format!("pgp key not found for {}", item).into());
+            }
+        }
+    }
+
+    let home = build_home()?;
+    let ret = build_entity(&home, entity, &keys, config);
+    remove_home(&home)?;
+
+    headers.extend_from_slice(&ret?);
+
+    return Ok(headers);
+}
+
+fn build_entity(
+    home: &Path,
+    mut entity: Vec<u8>,
+    keys: &[PathBuf],
+    config: &Pgp,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !config.key.is_empty() {
+        entity = sign(home, &entity, config)?;
+    }
+
+    if !keys.is_empty() {
+        entity = encrypt(home, &entity, keys)?;
+    }
+
+    return Ok(entity);
+}
+
+fn sign(home: &Path, entity: &[u8], config: &Pgp) -> Result<Vec<u8>, Box<dyn Error>> {
+    run_gpg(home, &["--import", config.key.as_str()], b"")?;
+
+    // The signed entity is hashed exactly as sent, trailing CRLF excluded (RFC 3156 section 5)
+    let signature = run_gpg(
+        home,
+        &[
+            "--pinentry-mode",
+            "loopback",
+            "--passphrase-fd",
+            "0",
+            "--digest-algo",
+            "SHA256",
+            "--armor",
+            "--detach-sign",
+            "--output",
+            "-",
+            write_entity(home, entity)?.to_str().unwrap_or_default(),
+        ],
+        config.passphrase.as_bytes(),
+    )?;
+
+    let boundary = build_boundary()?;
+    let mut buf = format!(
+        "Content-Type: multipart/signed; micalg=pgp-sha256;\r\n \
+         protocol=\"application/pgp-signature\"; boundary=\"{}\"\r\n\r\n--{}\r\n",
+        boundary, boundary
+    )
+    .into_bytes();
+
+    buf.extend_from_slice(entity);
+    buf.extend_from_slice(
+        format!(
+            "\r\n--{}\r\n\
+             Content-Type: application/pgp-signature; name=\"signature.asc\"\r\n\
+             Content-Description: OpenPGP digital signature\r\n\
+             Content-Disposition: attachment; filename=\"signature.asc\"\r\n\r\n\
+             {}\r\n--{}--\r\n",
+            boundary,
+            String::from_utf8(normalize_newlines(&signature))?.trim_end(),
+            boundary
+        )
+        .as_bytes(),
+    );
+
+    return Ok(buf);
+}
+
+fn encrypt(home: &Path, entity: &[u8], keys: &[PathBuf]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut args: Vec<&str> = vec!["--trust-model", "always", "--armor", "--output", "-"];
+
+    for item in keys {
+        args.push("--recipient-file");
+        args.push(item.to_str().unwrap_or_default());
+    }
+
+    let name = write_entity(home, entity)?;
+    args.push("--encrypt");
+    args.push(name.to_str().unwrap_or_default());
+
+    let data = run_gpg(home, &args, b"")?;
+
+    let boundary = build_boundary()?;
+    let buf = format!(
+        "Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\";\r\n \
+         boundary=\"{}\"\r\n\r\n\
+         --{}\r\n\
+         Content-Type: application/pgp-encrypted\r\n\
+         Content-Description: PGP/MIME version identification\r\n\r\n\
+         Version: 1\r\n\r\n\
+         --{}\r\n\
+         Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n\
+         Content-Description: OpenPGP encrypted message\r\n\
+         Content-Disposition: inline; filename=\"encrypted.asc\"\r\n\r\n\
+         {}\r\n--{}--\r\n",
+        boundary,
+        boundary,
+        boundary,
+        String::from_utf8(normalize_newlines(&data))?.trim_end(),
+        boundary
+    );
+
+    return Ok(buf.into_bytes());
+}
+
+fn build_home() -> Result<PathBuf, Box<dyn Error>> {
+    let home = env::temp_dir().join(format!("sender-gnupg-{}", &build_boundary()?[..16]));
+
+    fs::create_dir_all(&home)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o700))?;
+    }
+
+    return Ok(home);
+}
+
+fn remove_home(home: &Path) -> Result<(), Box<dyn Error>> {
+    // Stop the agent gpg started for this home before removing it
+    let _ = Command::new("gpgconf")
+        .arg("--homedir")
+        .arg(home)
+        .args(["--kill", "gpg-agent"])
+        .output();
+
+    return fs::remove_dir_all(home).map_err(|e| e.into());
+}
+
+fn write_entity(home: &Path, entity: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
+    let name = home.join("entity.txt");
+    fs::write(&name, entity)?;
+
+    return Ok(name);
+}
+
+fn run_gpg(home: &Path, args: &[&str], input: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut child = Command::new(GPG_BINARY)
+        .arg("--batch")
+        .arg("--yes")
+        .arg("--quiet")
+        .arg("--homedir")
+        .arg(home)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {}", GPG_BINARY, e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} failed: {}", GPG_BINARY, err.trim()).into());
+    }
+
+    return Ok(output.stdout);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::{split_headers, split_message};
+
+    const DATA: &[u8] = b"From: sender@example.com\r\n\
+                          To: bob@example.com\r\n\
+                          Subject: Security advisory\r\n\
+                          MIME-Version: 1.0\r\n\
+                          Content-Type: text/plain; charset=utf-8\r\n\
+                          Content-Transfer-Encoding: 7bit\r\n\
+                          \r\n\
+                          Please upgrade to 1.0.1.\r\n";
+
+    const ENTITY: &[u8] = b"Content-Type: text/plain; charset=utf-8\r\n\
+                            Content-Transfer-Encoding: 7bit\r\n\
+                            \r\n\
+                            Please upgrade to 1.0.1.\r\n";
+
+    fn build_config(key: &str, keyring: &str, allow_unencrypted: bool) -> Pgp {
+        Pgp {
+            allow_unencrypted,
+            key: key.to_string(),
+            keyring: keyring.to_string(),
+            passphrase: "".to_string(),
+        }
+    }
+
+    fn find_boundary(data: &[u8]) -> String {
+        let (head, _) = split_message(data);
+        let headers = split_headers(std::str::from_utf8(head).unwrap());
+        let (_, content_type) = headers.iter().find(|(n, _)| n == "Content-Type").unwrap();
+        let boundary = content_type.split("boundary=\"").nth(1).unwrap();
+
+        format!("--{}", boundary.trim_end_matches('"'))
+    }
+
+    fn has_gpg() -> bool {
+        Command::new(GPG_BINARY).arg("--version").output().is_ok()
+    }
+
+    #[test]
+    fn test_sign() {
+        assert!(has_gpg(), "{} is required to run this test", GPG_BINARY);
+
+        let config = build_config("test/pgp.asc", "", false);
+        let ret = protect(DATA, &["bob@example.com".to_string()], &config).unwrap();
+        let text = String::from_utf8(ret.clone()).unwrap();
+        assert!(text.contains("Content-Type: multipart/signed; micalg=pgp-sha256;"));
+        assert!(text.contains("Subject: Security advisory\r\n"));
+
+        let delimiter = find_boundary(&ret);
+        let parts: Vec<&str> = text.split(delimiter.as_str()).collect();
+        assert_eq!(parts.len(), 4);
+
+        let entity = parts[1]
+            .trim_start_matches("\r\n")
+            .strip_suffix("\r\n")
+            .unwrap();
+        assert_eq!(entity.as_bytes(), ENTITY);
+
+        let (_, signature) = split_message(parts[2].trim_start_matches("\r\n").as_bytes());
+
+        let home = build_home().unwrap();
+        run_gpg(&home, &["--import", "test/pgp.asc"], b"").unwrap();
+        fs::write(home.join("signature.asc"), signature).unwrap();
+        fs::write(home.join("entity.txt"), entity).unwrap();
+        let sig = home.join("signature.asc");
+        let data = home.join("entity.txt");
+        let args = ["--verify", sig.to_str().unwrap(), data.to_str().unwrap()];
+        assert!(run_gpg(&home, &args, b"").is_ok());
+
+        fs::write(&data, DATA).unwrap();
+        assert!(run_gpg(&home, &args, b"").is_err());
+        remove_home(&home).unwrap();
+    }
+
+    #[test]
+    fn test_encrypt() {
+        assert!(has_gpg(), "{} is required to run this test", GPG_BINARY);
+
+        let config = build_config("test/pgp.asc", "test/keyring", false);
+        let ret = protect(DATA, &["Bob@example.com".to_string()], &config).unwrap();
+        let text = String::from_utf8(ret.clone()).unwrap();
+        assert!(text.contains("multipart/encrypted; protocol=\"application/pgp-encrypted\""));
+        assert!(!text.contains("Please upgrade"));
+
+        let delimiter = find_boundary(&ret);
+        let parts: Vec<&str> = text.split(delimiter.as_str()).collect();
+        assert_eq!(parts.len(), 4);
+        let (_, encrypted) = split_message(parts[2].trim_start_matches("\r\n").as_bytes());
+
+        let home = build_home().unwrap();
+        run_gpg(&home, &["--import", "test/bob.asc"], b"").unwrap();
+        let ret = run_gpg(&home, &["--decrypt"], encrypted).unwrap();
+        remove_home(&home).unwrap();
+
+        let ret = String::from_utf8(ret).unwrap();
+        assert!(ret.starts_with("Content-Type: multipart/signed;"));
+        assert!(ret.contains("Please upgrade to 1.0.1."));
+
+        let ret = protect(DATA, &["carla@example.com".to_string()], &config);
+        assert!(ret.is_err());
+
+        let config = build_config("", "test/keyring", true);
+        let ret = protect(DATA, &["carla@example.com".to_string()], &config).unwrap();
+        assert_eq!(ret, DATA);
+    }
+}
@@ -0,0 +1,610 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawHandle;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lettre::address::Envelope;
+use openssl::base64;
+
+use crate::lmtp;
+use crate::mime::build_boundary;
+use crate::retry;
+use crate::Queue;
+
+pub const DEAD_DIR: &str = "dead";
+pub const QUEUE_DIR: &str = "queue";
+pub const TMP_DIR: &str = "tmp";
+
+const LOCK_FILE: &str = "lock";
+
+const DEFAULT_DELAY: u64 = 300;
+const DEFAULT_EXPIRY: u64 = 5 * 24 * 3600;
+const MAX_DELAY: u64 = 4 * 3600;
+
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Clone, Debug)]
+pub struct Entry {
+    pub attempts: u32,
+    pub created: u64,
+    pub data: String,
+    pub envelope: Envelope,
+    pub error: String,
+    pub next: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Item {
+    pub dir: String,
+    pub entry: Entry,
+    pub id: String,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub dead: usize,
+    pub deferred: usize,
+    pub sent: usize,
+}
+
+pub fn enqueue(config: &Queue, envelope: &Envelope, data: &[u8]) -> Result<String, Box<dyn Error>> {
+    let now = now()?;
+    let id = format!("{}-{}", now, &build_boundary()?[..16]);
+
+    let entry = Entry {
+        attempts: 0,
+        created: now,
+        data: base64::encode_block(data),
+        envelope: envelope.clone(),
+        error: "".to_string(),
+        next: now,
+    };
+
+    write_entry(config, QUEUE_DIR, &id, &entry)?;
+
+    return Ok(id);
+}
+
+pub fn list(config: &Queue) -> Result<Vec<Item>, Box<dyn Error>> {
+    let mut buf = Vec::new();
+
+    for dir in [QUEUE_DIR, DEAD_DIR] {
+        for (id, path) in read_ids(config, dir)? {
+            match read_entry(&path) {
+                Ok(entry) => buf.push(Item {
+                    dir: dir.to_string(),
+                    entry,
+                    id,
+                }),
+                Err(e) => eprintln!("{}: unreadable: {}", id, e),
+            }
+        }
+    }
+
+    return Ok(buf);
+}
+
+// Each due entry gets one delivery attempt per run, and the backoff between runs
+// doubles from delay up to four hours until the entry expires
+pub fn run<F>(config: &Queue, force: bool, mut send: F) -> Result<Summary, Box<dyn Error>>
+where
+    F: FnMut(&Envelope, &[u8]) -> Result<(), Box<dyn Error>>,
+{
+    let _lock = lock(config)?;
+    let mut summary = Summary::default();
+    let expiry = if config.expiry > 0 {
+        config.expiry
+    } else {
+        DEFAULT_EXPIRY
+    };
+
+    for (id, path) in read_ids(config, QUEUE_DIR)? {
+        let now = now()?;
+        let mut entry = match read_entry(&path) {
+            Ok(e) => e,
+            Err(e) => {
+                discard(config, &id, &path)?;
+                eprintln!("{}: dead: {}", id, e);
+                summary.dead += 1;
+                continue;
+            }
+        };
+
+        if !force && entry.next > now {
+            continue;
+        }
+
+        if now >= entry.created + expiry {
+            entry.error = format!("expired after {} attempts: {}", entry.attempts, entry.error);
+            bury(config, &id, &entry, &path)?;
+            eprintln!("{}: dead: {}", id, entry.error);
+            summary.dead += 1;
+            continue;
+        }
+
+        let data = match base64::decode_block(&entry.data) {
+            Ok(d) => d,
+            Err(e) => {
+                entry.error = format!("invalid data: {}", e);
+                bury(config, &id, &entry, &path)?;
+                eprintln!("{}: dead: {}", id, entry.error);
+                summary.dead += 1;
+                continue;
+            }
+        };
+        entry.attempts += 1;
+
+        let err = match send(&entry.envelope, &data) {
+            Ok(_) => {
+                fs::remove_file(&path)?;
+                println!("{}: sent", id);
+                summary.sent += 1;
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        // Recipients rejected outright are dropped so only the deferred ones stay queued
+        entry.error = retry::describe(err.as_ref());
+        if let Some(e) = err.downcast_ref().filter(|e| lmtp::is_transient(e)) {
+            for item in lmtp::rejected(e) {
+                eprintln!(
+                    "{}: rejected {}: {} {}",
+                    id, item.recipient, item.code, item.text
+                );
+            }
+            entry.envelope = lmtp::remaining(e, &entry.envelope)?;
+        }
+
+        if !retry::is_transient(err.as_ref())
+            || (config.attempts > 0 && entry.attempts >= config.attempts)
+        {
+            bury(config, &id, &entry, &path)?;
+            eprintln!("{}: dead: {}", id, entry.error);
+            summary.dead += 1;
+            continue;
+        }
+
+        entry.next = now + backoff(config, entry.attempts);
+        write_entry(config, QUEUE_DIR, &id, &entry)?;
+        eprintln!(
+            "{}: deferred (attempt {}): {}",
+            id, entry.attempts, entry.error
+        );
+        summary.deferred += 1;
+    }
+
+    return Ok(summary);
+}
+
+fn backoff(config: &Queue, attempts: u32) -> u64 {
+    let delay = if config.delay > 0 {
+        config.delay
+    } else {
+        DEFAULT_DELAY
+    };
+
+    return delay
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_DELAY);
+}
+
+fn bury(config: &Queue, id: &str, entry: &Entry, path: &Path) -> Result<(), Box<dyn Error>> {
+    write_entry(config, DEAD_DIR, id, entry)?;
+
+    return fs::remove_file(path).map_err(|e| e.into());
+}
+
+// Runs hold an exclusive lock on the spool so a daemon and a manual flush never
+// deliver the same entry twice, and the kernel releases it if the process dies
+fn lock(config: &Queue) -> Result<File, Box<dyn Error>> {
+    fs::create_dir_all(&config.dir)?;
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(Path::new(&config.dir).join(LOCK_FILE))?;
+
+    lock_file(&file).map_err(|e| format!("failed to lock queue: {}", e))?;
+
+    return Ok(file);
+}
+
+#[cfg(unix)]
+fn lock_file(file: &File) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    return Ok(());
+}
+
+// Every run locks the same first byte, which is enough to exclude the others
+#[cfg(windows)]
+fn lock_file(file: &File) -> io::Result<()> {
+    use windows_sys::Win32::Storage::FileSystem::{LockFileEx, LOCKFILE_EXCLUSIVE_LOCK};
+    use windows_sys::Win32::System::IO::OVERLAPPED;
+
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        LockFileEx(
+            file.as_raw_handle() as _,
+            LOCKFILE_EXCLUSIVE_LOCK,
+            0,
+            1,
+            0,
+            &mut overlapped,
+        )
+    };
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    return Ok(());
+}
+
+// An unreadable entry is moved aside untouched so it neither blocks the queue nor gets lost
+fn discard(config: &Queue, id: &str, path: &Path) -> Result<(), Box<dyn Error>> {
+    let target = Path::new(&config.dir).join(DEAD_DIR);
+    fs::create_dir_all(&target)?;
+
+    return fs::rename(path, target.join(format!("{}.json", id))).map_err(|e| e.into());
+}
+
+fn write_entry(config: &Queue, dir: &str, id: &str, entry: &Entry) -> Result<(), Box<dyn Error>> {
+    let tmp = Path::new(&config.dir).join(TMP_DIR);
+    let target = Path::new(&config.dir).join(dir);
+
+    fs::create_dir_all(&tmp)?;
+    fs::create_dir_all(&target)?;
+
+    // Entries only appear in the queue once complete, since rename is atomic
+    // within one file system
+    let name = format!("{}.json", id);
+    fs::write(tmp.join(&name), serde_json::to_vec(entry)?)?;
+
+    return fs::rename(tmp.join(&name), target.join(&name)).map_err(|e| e.into());
+}
+
+fn read_entry(path: &Path) -> Result<Entry, Box<dyn Error>> {
+    let data = fs::read(path)?;
+
+    return serde_json::from_slice(&data).map_err(|e| format!("{}: {}", path.display(), e).into());
+}
+
+fn read_ids(config: &Queue, dir: &str) -> Result<Vec<(String, PathBuf)>, Box<dyn Error>> {
+    let path = Path::new(&config.dir).join(dir);
+    let mut buf = Vec::new();
+
+    if !path.is_dir() {
+        return Ok(buf);
+    }
+
+    for item in fs::read_dir(path)? {
+        let path = item?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+            buf.push((id.to_string(), path.clone()));
+        }
+    }
+
+    // Ids start with the enqueue time so this keeps delivery in order
+    buf.sort();
+
+    return Ok(buf);
+}
+
+fn now() -> Result<u64, Box<dyn Error>> {
+    return Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn build_config(attempts: u32, expiry: u64) -> Queue {
+        let dir =
+            env::temp_dir().join(format!("sender-queue-{}", &build_boundary().unwrap()[..16]));
+
+        Queue {
+            attempts,
+            delay: 60,
+            dir: dir.to_str().unwrap().to_string(),
+            expiry,
+            interval: 0,
+        }
+    }
+
+    fn build_envelope() -> Envelope {
+        Envelope::new(
+            Some("sender@example.com".parse().unwrap()),
+            vec!["alen@example.com".parse().unwrap()],
+        )
+        .unwrap()
+    }
+
+    fn build_error(code: &str) -> Box<dyn Error> {
+        // Replies are only constructed by lettre, so borrow one from a stub exchange
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let reply = format!("{} rejected\r\n", code);
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let ret = if line.starts_with("MAIL") {
+                    reply.as_str()
+                } else {
+                    "250 OK\r\n"
+                };
+                line.clear();
+                if stream.write_all(ret.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let transport = lettre::SmtpTransport::builder_dangerous("127.0.0.1")
+            .port(port)
+            .build();
+        lettre::Transport::send_raw(&transport, &build_envelope(), b"")
+            .unwrap_err()
+            .into()
+    }
+
+    #[test]
+    fn test_enqueue() {
+        let config = build_config(0, 0);
+        let id = enqueue(
+            &config,
+            &build_envelope(),
+            b"Subject: Build 42\r\n\r\nbody\r\n",
+        )
+        .unwrap();
+
+        let path = Path::new(&config.dir)
+            .join(QUEUE_DIR)
+            .join(format!("{}.json", id));
+        assert!(path.is_file());
+        assert_eq!(
+            fs::read_dir(Path::new(&config.dir).join(TMP_DIR))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let ret = list(&config).unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].dir, QUEUE_DIR);
+        assert_eq!(ret[0].id, id);
+        assert_eq!(ret[0].entry.envelope, build_envelope());
+        assert_eq!(
+            base64::decode_block(&ret[0].entry.data).unwrap(),
+            b"Subject: Build 42\r\n\r\nbody\r\n"
+        );
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_run() {
+        let config = build_config(2, 0);
+        enqueue(&config, &build_envelope(), b"first").unwrap();
+        enqueue(&config, &build_envelope(), b"second").unwrap();
+
+        let transient = build_error("451");
+        let ret = run(&config, false, |_, data| match data {
+            b"first" => Ok(()),
+            _ => Err(build_error("451")),
+        })
+        .unwrap();
+        assert_eq!(
+            ret,
+            Summary {
+                dead: 0,
+                deferred: 1,
+                sent: 1
+            }
+        );
+        assert!(retry::is_transient(transient.as_ref()));
+
+        let ret = list(&config).unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].entry.attempts, 1);
+        assert!(ret[0].entry.next > ret[0].entry.created);
+        assert!(ret[0].entry.error.starts_with("transient 451"));
+
+        // Not due yet unless forced
+        let ret = run(&config, false, |_, _| Ok(())).unwrap();
+        assert_eq!(ret, Summary::default());
+
+        let ret = run(&config, true, |_, _| Err(build_error("451"))).unwrap();
+        assert_eq!(
+            ret,
+            Summary {
+                dead: 1,
+                deferred: 0,
+                sent: 0
+            }
+        );
+
+        let ret = list(&config).unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].dir, DEAD_DIR);
+
+        enqueue(&config, &build_envelope(), b"third").unwrap();
+        let ret = run(&config, false, |_, _| Err(build_error("550"))).unwrap();
+        assert_eq!(
+            ret,
+            Summary {
+                dead: 1,
+                deferred: 0,
+                sent: 0
+            }
+        );
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_lmtp() {
+        let config = build_config(0, 0);
+        let envelope = Envelope::new(
+            Some("sender@example.com".parse().unwrap()),
+            vec![
+                "alen@example.com".parse().unwrap(),
+                "bob@example.com".parse().unwrap(),
+            ],
+        )
+        .unwrap();
+        enqueue(&config, &envelope, b"first").unwrap();
+
+        let build_failure = |code, recipient: &str| lmtp::Failure {
+            code,
+            recipient: recipient.to_string(),
+            text: "rejected".to_string(),
+        };
+
+        let ret = run(&config, false, |_, _| {
+            Err(Box::new(lmtp::DeliveryError {
+                failures: vec![
+                    build_failure(550, "alen@example.com"),
+                    build_failure(452, "bob@example.com"),
+                ],
+                total: 2,
+            }))
+        })
+        .unwrap();
+        assert_eq!(ret.deferred, 1);
+
+        let ret = list(&config).unwrap();
+        assert_eq!(ret[0].dir, QUEUE_DIR);
+        assert_eq!(ret[0].entry.envelope.to().len(), 1);
+        assert_eq!(ret[0].entry.envelope.to()[0].to_string(), "bob@example.com");
+
+        let ret = run(&config, true, |_, _| {
+            Err(Box::new(lmtp::DeliveryError {
+                failures: vec![build_failure(550, "bob@example.com")],
+                total: 1,
+            }))
+        })
+        .unwrap();
+        assert_eq!(ret.dead, 1);
+        assert_eq!(list(&config).unwrap()[0].dir, DEAD_DIR);
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_expiry() {
+        let mut config = build_config(0, 0);
+        let id = enqueue(&config, &build_envelope(), b"first").unwrap();
+
+        let path = Path::new(&config.dir)
+            .join(QUEUE_DIR)
+            .join(format!("{}.json", id));
+        let mut entry = read_entry(&path).unwrap();
+        entry.created -= 3600;
+        write_entry(&config, QUEUE_DIR, &id, &entry).unwrap();
+
+        config.expiry = 60;
+        let ret = run(&config, false, |_, _| Ok(())).unwrap();
+        assert_eq!(
+            ret,
+            Summary {
+                dead: 1,
+                deferred: 0,
+                sent: 0
+            }
+        );
+
+        let ret = list(&config).unwrap();
+        assert!(ret[0].entry.error.starts_with("expired after 0 attempts"));
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_corrupt() {
+        let config = build_config(0, 0);
+        enqueue(&config, &build_envelope(), b"first").unwrap();
+
+        let path = Path::new(&config.dir)
+            .join(QUEUE_DIR)
+            .join("0-corrupt.json");
+        fs::write(&path, b"{\"attempts\":").unwrap();
+
+        let ret = run(&config, false, |_, _| Ok(())).unwrap();
+        assert_eq!(
+            ret,
+            Summary {
+                dead: 1,
+                deferred: 0,
+                sent: 1
+            }
+        );
+        assert!(!path.exists());
+        assert!(Path::new(&config.dir)
+            .join(DEAD_DIR)
+            .join("0-corrupt.json")
+            .is_file());
+        assert!(list(&config).unwrap().is_empty());
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_lock() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let config = build_config(0, 0);
+        enqueue(&config, &build_envelope(), b"first").unwrap();
+
+        let file = lock(&config).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let queue = config.clone();
+        std::thread::spawn(move || {
+            tx.send(run(&queue, false, |_, _| Ok(())).unwrap()).unwrap();
+        });
+
+        // The second run waits until the first one lets go of the spool
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(file);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().sent, 1);
+
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = build_config(0, 0);
+        assert_eq!(backoff(&config, 1), 60);
+        assert_eq!(backoff(&config, 3), 240);
+        assert_eq!(backoff(&config, 30), MAX_DELAY);
+    }
+}
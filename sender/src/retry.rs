@@ -0,0 +1,196 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+use openssl::rand::rand_bytes;
+
+use crate::lmtp;
+use crate::sendmail;
+use crate::Retry;
+
+const DEFAULT_MULTIPLIER: f64 = 2.0;
+
+pub fn run<F>(config: Option<&Retry>, mut send: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut() -> Result<(), Box<dyn Error>>,
+{
+    let attempts = config.map_or(1, |r| r.attempts.max(1));
+    let mut attempt = 1;
+
+    loop {
+        let err = match send() {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let transient = is_transient(err.as_ref());
+        let config = match config {
+            Some(c) if transient && attempt < attempts => c,
+            _ => {
+                if attempts > 1 {
+                    eprintln!(
+                        "attempt {}/{}: {}",
+                        attempt,
+                        attempts,
+                        describe(err.as_ref())
+                    );
+                }
+                return Err(err);
+            }
+        };
+
+        let delay = backoff(config, attempt, random()?);
+        eprintln!(
+            "attempt {}/{}: {}, retrying in {}ms",
+            attempt,
+            attempts,
+            describe(err.as_ref()),
+            delay.as_millis()
+        );
+
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+// 4xx replies, lost connections and sendmail's EX_TEMPFAIL may pass on a later attempt,
+// while 5xx replies, TLS setup and local failures will not (RFC 5321 section 4.2.1)
+pub fn is_transient(err: &(dyn Error + 'static)) -> bool {
+    if let Some(e) = err.downcast_ref::<lmtp::DeliveryError>() {
+        return lmtp::is_transient(e);
+    }
+    if let Some(e) = err.downcast_ref::<sendmail::ExitError>() {
+        return sendmail::is_transient(e);
+    }
+
+    match err.downcast_ref::<lettre::transport::smtp::Error>() {
+        Some(e) => {
+            e.is_transient()
+                || e.is_timeout()
+                || !(e.is_permanent() || e.is_client() || e.is_response() || e.is_tls())
+        }
+        None => false,
+    }
+}
+
+pub fn describe(err: &(dyn Error + 'static)) -> String {
+    let kind = if is_transient(err) {
+        "transient"
+    } else {
+        "permanent"
+    };
+
+    match err.downcast_ref::<lettre::transport::smtp::Error>() {
+        Some(e) => match (e.status(), e.source()) {
+            (Some(c), Some(s)) => format!("{} {}: {}", kind, c, s.to_string().trim()),
+            (Some(c), None) => format!("{} {}", kind, c),
+            (None, _) => format!("{}: {}", kind, e),
+        },
+        None => format!("{}: {}", kind, err),
+    }
+}
+
+// The delay grows by the multiplier per attempt up to max_delay, and jitter spreads
+// it by up to the given fraction either way so queued senders do not retry in step
+pub fn backoff(config: &Retry, attempt: u32, random: f64) -> Duration {
+    let multiplier = if config.multiplier > 0.0 {
+        config.multiplier
+    } else {
+        DEFAULT_MULTIPLIER
+    };
+
+    let mut delay = config.delay as f64 * multiplier.powi(attempt as i32 - 1);
+    if config.max_delay > 0 {
+        delay = delay.min(config.max_delay as f64);
+    }
+
+    let jitter = config.jitter.clamp(0.0, 1.0);
+    delay *= 1.0 + jitter * (2.0 * random - 1.0);
+
+    return Duration::from_millis(delay.max(0.0) as u64);
+}
+
+fn random() -> Result<f64, Box<dyn Error>> {
+    let mut buf = [0u8; 8];
+    rand_bytes(&mut buf)?;
+
+    return Ok(u64::from_le_bytes(buf) as f64 / u64::MAX as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_config(attempts: u32, jitter: f64, max_delay: u64) -> Retry {
+        Retry {
+            attempts,
+            delay: 100,
+            jitter,
+            max_delay,
+            multiplier: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = build_config(5, 0.0, 0);
+        assert_eq!(backoff(&config, 1, 0.3), Duration::from_millis(100));
+        assert_eq!(backoff(&config, 2, 0.3), Duration::from_millis(200));
+        assert_eq!(backoff(&config, 4, 0.3), Duration::from_millis(800));
+
+        let config = build_config(5, 0.0, 300);
+        assert_eq!(backoff(&config, 4, 0.3), Duration::from_millis(300));
+
+        let config = build_config(5, 0.5, 0);
+        assert_eq!(backoff(&config, 1, 0.0), Duration::from_millis(50));
+        assert_eq!(backoff(&config, 1, 0.5), Duration::from_millis(100));
+        assert_eq!(backoff(&config, 1, 1.0), Duration::from_millis(150));
+
+        let r = random().unwrap();
+        assert!((0.0..=1.0).contains(&r));
+    }
+
+    #[test]
+    fn test_run() {
+        let config = Retry {
+            delay: 1,
+            ..build_config(3, 0.2, 0)
+        };
+
+        let mut count = 0;
+        let ret = run(Some(&config), || {
+            count += 1;
+            Err("local failure".into())
+        });
+        assert!(ret.is_err());
+        assert_eq!(count, 1);
+
+        let mut count = 0;
+        let ret = run(None, || {
+            count += 1;
+            Ok(())
+        });
+        assert!(ret.is_ok());
+        assert_eq!(count, 1);
+
+        assert!(!is_transient(
+            Box::<dyn Error>::from("local failure").as_ref()
+        ));
+        assert_eq!(
+            describe(Box::<dyn Error>::from("local failure").as_ref()),
+            "permanent: local failure"
+        );
+    }
+}
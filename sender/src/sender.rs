@@ -12,16 +12,45 @@
 
 extern crate clap;
 
+mod calendar;
+mod dkim;
+mod lmtp;
+mod maildir;
+mod mime;
+mod oauth2;
+mod pgp;
+mod queue;
+mod retry;
+mod sendmail;
+mod smime;
+mod thread;
+
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::fs;
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use lazy_static::lazy_static;
-use lettre::message::{header::ContentType, Attachment, Mailbox, Mailboxes, MultiPart, SinglePart};
+use lettre::address::Envelope;
+use lettre::message::{
+    header,
+    header::{ContentTransferEncoding, ContentType, HeaderName, HeaderValue},
+    Attachment, Body, Mailbox, Mailboxes, MultiPart, SinglePart,
+};
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{Certificate, Identity, Tls as ClientTls, TlsParameters};
 use lettre::{Message, SmtpTransport, Transport};
+use mail_parser::MessageParser;
+use minijinja::{path_loader, AutoEscape, Environment, UndefinedBehavior};
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::x509::X509;
+use regex::Regex;
+use serde_json::{Map, Value};
 
 #[derive(serde_derive::Deserialize, Debug)]
 struct Config {
@@ -31,38 +60,423 @@ struct Config {
     pass: String,
     sender: String,
     sep: String,
+    auth: Option<Auth>,
+    dkim: Option<Dkim>,
+    pgp: Option<Pgp>,
+    queue: Option<Queue>,
+    retry: Option<Retry>,
+    smime: Option<Smime>,
+    tls: Option<Tls>,
+    transport: Option<Delivery>,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+struct Auth {
+    #[serde(default)]
+    client_id: String,
+    #[serde(default)]
+    client_secret: String,
+    mechanism: String,
+    #[serde(default)]
+    refresh_token: String,
+    #[serde(default)]
+    scope: String,
+    #[serde(default)]
+    token_url: String,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+struct Dkim {
+    canonicalization: String,
+    domain: String,
+    headers: Vec<String>,
+    key: String,
+    selector: String,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+struct Pgp {
+    #[serde(default)]
+    allow_unencrypted: bool,
+    key: String,
+    keyring: String,
+    passphrase: String,
+}
+
+#[derive(serde_derive::Deserialize, Clone, Debug)]
+struct Queue {
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default)]
+    delay: u64,
+    dir: String,
+    #[serde(default)]
+    expiry: u64,
+    #[serde(default)]
+    interval: u64,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+struct Retry {
+    attempts: u32,
+    delay: u64,
+    #[serde(default)]
+    jitter: f64,
+    #[serde(default)]
+    max_delay: u64,
+    #[serde(default)]
+    multiplier: f64,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+struct Smime {
+    cert: String,
+    certs: String,
+    key: String,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+struct Tls {
+    #[serde(default)]
+    ca: String,
+    #[serde(default)]
+    cert: String,
+    #[serde(default)]
+    insecure: bool,
+    #[serde(default)]
+    key: String,
+    mode: String,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+struct Delivery {
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    command: String,
+    #[serde(default)]
+    dir: String,
+    kind: String,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+struct Attach {
+    content_type: String,
+    name: String,
+    path: String,
+}
+
+enum Mailer {
+    File(PathBuf),
+    Lmtp(lmtp::Client),
+    Maildir(String),
+    Queue(Queue),
+    Sendmail(String),
+    Smtp(SmtpTransport),
+    Stdout,
+}
+
+#[derive(Clone, Debug)]
+struct Event {
+    end: String,
+    location: String,
+    method: String,
+    sequence: u32,
+    start: String,
+    timezone: String,
+    uid: String,
+}
+
+#[derive(Clone, Debug, Default)]
 struct Mail {
-    attachment: Vec<String>,
+    attachment: Vec<Attach>,
+    bcc: Vec<String>,
     body: String,
+    calendar: Option<Event>,
     cc: Vec<String>,
     content_type: String,
     from: String,
+    headers: Vec<(String, String)>,
+    in_reply_to: String,
+    inline: Vec<Attach>,
+    message_id: String,
+    references: Vec<String>,
+    reply_to: Vec<String>,
     subject: String,
+    text_body: String,
     to: Vec<String>,
 }
 
+type Login = (Credentials, Vec<Mechanism>);
+
+const ALTERNATIVE_CONTENT_TYPE: &str = "multipart/alternative";
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+const HTML_CONTENT_TYPE: &str = "text/html";
+const MESSAGE_CONTENT_TYPE: &str = "message/rfc822";
+const QUEUE_INTERVAL: u64 = 60;
+const TEXT_WIDTH: usize = 78;
+
+const AUTH_LOGIN: &str = "LOGIN";
+const AUTH_NONE: &str = "NONE";
+const AUTH_PLAIN: &str = "PLAIN";
+const AUTH_XOAUTH2: &str = "XOAUTH2";
+
+const TLS_IMPLICIT: &str = "implicit";
+const TLS_NONE: &str = "none";
+const TLS_STARTTLS_OPPORTUNISTIC: &str = "starttls-opportunistic";
+const TLS_STARTTLS_REQUIRED: &str = "starttls-required";
+
+const TRANSPORT_LMTP: &str = "lmtp";
+const TRANSPORT_MAILDIR: &str = "maildir";
+const TRANSPORT_SENDMAIL: &str = "sendmail";
+const TRANSPORT_SMTP: &str = "smtp";
+
+const RESERVED_HEADERS: [&str; 19] = [
+    "Bcc",
+    "Cc",
+    "Content-Disposition",
+    "Content-ID",
+    "Content-Transfer-Encoding",
+    "Content-Type",
+    "DKIM-Signature",
+    "Date",
+    "From",
+    "In-Reply-To",
+    "MIME-Version",
+    "Message-ID",
+    "Received",
+    "References",
+    "Reply-To",
+    "Return-Path",
+    "Sender",
+    "Subject",
+    "To",
+];
+
 lazy_static! {
     static ref CONTENT_TYPE_MAP: HashMap<&'static str, &'static str> = {
         let mut m = HashMap::new();
+        m.insert("ALTERNATIVE", "multipart/alternative");
         m.insert("HTML", "text/html");
         m.insert("PLAIN_TEXT", "text/plain");
         m
     };
+    static ref IMAGE_SOURCE_REGEX: Regex =
+        Regex::new(r#"(?i)<img\b[^>]*?\ssrc\s*=\s*("[^"]*"|'[^']*')"#).unwrap();
+    static ref URL_SCHEME_REGEX: Regex = Regex::new(r"^[A-Za-z][A-Za-z0-9+.-]+:").unwrap();
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let app = Command::new("mail sender")
+    let app = build_command().get_matches();
+
+    if let Some(sub) = app.subcommand_matches("verify") {
+        let eml: &String = sub.get_one("eml").unwrap();
+        let key: &String = sub.get_one("key").unwrap();
+        let ret = dkim::verify(&fs::read(check_file(eml)?)?, &fs::read(check_file(key)?)?)?;
+        println!("dkim: pass ({})", ret);
+        return Ok(());
+    }
+
+    let default = "".to_string();
+
+    let mut config = Config {
+        host: "localhost".to_string(),
+        port: 25,
+        user: "".to_string(),
+        pass: "".to_string(),
+        sender: "sender@example.com".to_string(),
+        sep: ",".to_string(),
+        auth: None,
+        dkim: None,
+        pgp: None,
+        queue: None,
+        retry: None,
+        smime: None,
+        tls: None,
+        transport: None,
+    };
+
+    let mut mail = Mail::default();
+
+    let c = app.get_one("config").unwrap_or(&default);
+    if let Ok(ret) = parse_config(c.as_str()) {
+        config = ret;
+    }
+
+    if let Some(sub) = app.subcommand_matches("queue") {
+        return run_queue(&config, sub);
+    }
+
+    if let Some(p) = config.pgp.as_mut() {
+        p.allow_unencrypted |= app.get_flag("allow_unencrypted");
+    }
+
+    parse_parts(&config, &app, &mut mail)?;
+    let body: &String = app.get_one("body").unwrap_or(&default);
+
+    let from = app.get_one("from").unwrap_or(&config.sender);
+    mail.from = parse_from(from.as_str())?;
+
+    let header: Vec<String> = app
+        .get_many("header")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    mail.headers = parse_headers(&header)?;
+
+    let list_id = app.get_one("list_id").unwrap_or(&default);
+    let unsubscribe = app.get_one("list_unsubscribe").unwrap_or(&default);
+    let precedence = app.get_one("precedence").unwrap_or(&default);
+    mail.headers.extend(parse_list_headers(
+        &config,
+        list_id.as_str(),
+        unsubscribe.as_str(),
+        precedence.as_str(),
+    )?);
+
+    let reply_to = app.get_one("reply_to").unwrap_or(&default);
+    mail.reply_to = parse_reply_to(&config, reply_to.as_str())?;
+
+    let suppression = app.get_one("suppression").unwrap_or(&default);
+    let suppression = parse_suppression(suppression.as_str())?;
+
+    let recipients = app.get_one("recipients").unwrap_or(&default);
+    let (bcc, cc, to) = parse_recipients(&config, recipients.as_str(), &suppression);
+
+    let merge = app.get_one("merge").unwrap_or(&default);
+    let resend = app.get_one("resend").unwrap_or(&default);
+    let keep = app.get_flag("keep_recipients");
+    if merge.is_empty() && !keep && bcc.len() == 0 && cc.len() == 0 && to.len() == 0 {
+        return Err(Box::from("failed to parse recipients"));
+    }
+
+    mail.bcc = bcc;
+    mail.cc = cc;
+    mail.to = to;
+
+    let method = app.get_one("calendar").unwrap_or(&default);
+    if !method.is_empty() {
+        let event = Event {
+            end: app.get_one("event_end").unwrap_or(&default).to_string(),
+            location: app
+                .get_one("event_location")
+                .unwrap_or(&default)
+                .to_string(),
+            method: method.to_string(),
+            sequence: match app.get_one::<u32>("event_sequence") {
+                Some(s) => *s,
+                None if method == calendar::CANCEL_METHOD => 1,
+                None => 0,
+            },
+            start: app.get_one("event_start").unwrap_or(&default).to_string(),
+            timezone: app
+                .get_one("event_timezone")
+                .unwrap_or(&default)
+                .to_string(),
+            uid: app.get_one("event_uid").unwrap_or(&default).to_string(),
+        };
+        mail.calendar = Some(parse_event(&config, event)?);
+    }
+
+    let text_body = app.get_one("text_body").unwrap_or(&default);
+    if let Ok(b) = parse_body(text_body.as_str()) {
+        mail.text_body = b;
+    }
+
+    let title = app.get_one("title").unwrap_or(&default);
+    mail.subject = (*title.to_owned()).parse().unwrap();
+
+    let in_reply_to = app.get_one("in_reply_to").unwrap_or(&default);
+    if !in_reply_to.is_empty() {
+        mail.in_reply_to = thread::parse_message_id(in_reply_to)?;
+    }
+
+    let references = app.get_one("references").unwrap_or(&default);
+    mail.references = parse_references(references.as_str())?;
+
+    let key = app.get_one("thread").unwrap_or(&default);
+    let state = app.get_one("thread_state").unwrap_or(&default);
+    let mut threads = thread::State::new();
+    if !key.is_empty() {
+        threads = thread::load_state(state.as_str())?;
+        thread_mail(&config, &mut mail, key.as_str(), &threads);
+    }
+
+    let vars = app.get_one("vars").unwrap_or(&default);
+    let var: Vec<String> = app.get_many("var").unwrap_or_default().cloned().collect();
+    let vars = parse_vars(vars.as_str(), &var)?;
+
+    let output = app.get_one("output_eml").unwrap_or(&default);
+    let dry_run = app.get_flag("dry_run");
+    let mut transport = build_transport(&config, output.as_str(), dry_run)?;
+    if app.get_flag("queue") {
+        let queue = config.queue.as_ref().ok_or("queue not configured")?;
+        transport = Mailer::Queue(queue.clone());
+    }
+
+    if !resend.is_empty() {
+        return resend_mail(
+            &config,
+            &transport,
+            &mail,
+            resend.as_str(),
+            keep,
+            &suppression,
+        );
+    }
+
+    let forward = app.get_one("forward").unwrap_or(&default);
+    if !forward.is_empty() {
+        forward_mail(&mut mail, forward.as_str())?;
+    }
+
+    if !merge.is_empty() {
+        return send_merge(
+            &config,
+            &transport,
+            &mail,
+            body.as_str(),
+            &vars,
+            merge.as_str(),
+            &suppression,
+        );
+    }
+
+    // Render even without variables so a stray {{ name }} fails instead of going out
+    if !app.get_flag("no_template") {
+        render_mail(&mut mail, body.as_str(), &vars)?;
+    }
+
+    if mail.content_type == ALTERNATIVE_CONTENT_TYPE || mail.content_type == HTML_CONTENT_TYPE {
+        mail.body = embed_inline(mail.body.as_str(), &body_dir(body), &mut mail.inline)?;
+    }
+
+    send_mail(&config, &transport, &mail)?;
+
+    if !key.is_empty() && !dry_run {
+        threads.insert(key.to_string(), mail.message_id.clone());
+        thread::save_state(state.as_str(), &threads)?;
+    }
+
+    return Ok(());
+}
+
+fn build_command() -> Command {
+    return Command::new("mail sender")
         .version("1.0.0")
         .author("Jia Jia")
+        .arg(
+            Arg::new("allow_unencrypted")
+                .long("allow-unencrypted")
+                .action(ArgAction::SetTrue)
+                .help("Send unencrypted when a recipient PGP key is missing"),
+        )
         .arg(
             Arg::new("attachment")
                 .long("attachment")
                 .short('a')
                 .value_name("NAME")
-                .help("Attachment files (attach1,attach2)"),
+                .help("Attachment files (attach1,attach2=name;type=application/pdf)"),
         )
         .arg(
             Arg::new("body")
@@ -71,6 +485,13 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .value_name("TEXT_OR_NAME")
                 .help("Body text or file"),
         )
+        .arg(
+            Arg::new("calendar")
+                .long("calendar")
+                .value_name("METHOD")
+                .value_parser([calendar::REQUEST_METHOD, calendar::CANCEL_METHOD])
+                .help("Calendar invitation method (REQUEST or CANCEL)"),
+        )
         .arg(
             Arg::new("config")
                 .long("config")
@@ -84,22 +505,199 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .short('e')
                 .default_value("PLAIN_TEXT")
                 .value_name("TYPE")
-                .help("Content type (HTML or PLAIN_TEXT)"),
+                .help("Content type (ALTERNATIVE, HTML or PLAIN_TEXT)"),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Print message instead of sending"),
+        )
+        .arg(
+            Arg::new("event_end")
+                .long("event-end")
+                .value_name("DATETIME")
+                .help("Event end (2026-10-20T16:00+08:00)")
+                .requires("calendar"),
+        )
+        .arg(
+            Arg::new("event_location")
+                .long("event-location")
+                .value_name("TEXT")
+                .help("Event location")
+                .requires("calendar"),
+        )
+        .arg(
+            Arg::new("event_sequence")
+                .long("event-sequence")
+                .value_name("NUM")
+                .value_parser(clap::value_parser!(u32))
+                .help("Event revision, increased on every update")
+                .requires("calendar"),
+        )
+        .arg(
+            Arg::new("event_start")
+                .long("event-start")
+                .value_name("DATETIME")
+                .help("Event start (2026-10-20T15:00+08:00)")
+                .requires("calendar"),
+        )
+        .arg(
+            Arg::new("event_timezone")
+                .long("event-timezone")
+                .value_name("TZID")
+                .help("Event time zone name for the start and end offsets (Asia/Shanghai)")
+                .requires("calendar"),
+        )
+        .arg(
+            Arg::new("event_uid")
+                .long("event-uid")
+                .value_name("UID")
+                .help("Event UID, required for CANCEL")
+                .requires("calendar"),
+        )
+        .arg(
+            Arg::new("forward")
+                .long("forward")
+                .value_name("NAME")
+                .help("Forward message file (.eml) as attachment")
+                .conflicts_with_all(["merge", "resend"]),
+        )
+        .arg(
+            Arg::new("from")
+                .long("from")
+                .short('f')
+                .value_name("MAILBOX")
+                .help("From mailbox (Alen Smith <alen@example.com>)"),
         )
         .arg(
             Arg::new("header")
-                .long("header")
+                .long("add-header")
                 .short('r')
+                .alias("header")
+                .value_name("TEXT")
+                .action(ArgAction::Append)
+                .help("Custom header (X-Build-Id: 1234) (repeatable)"),
+        )
+        .arg(
+            Arg::new("in_reply_to")
+                .long("in-reply-to")
+                .value_name("MSGID")
+                .help("In-Reply-To message id (<build-41@example.com>)"),
+        )
+        .arg(
+            Arg::new("inline")
+                .long("inline")
+                .short('i')
+                .value_name("NAME")
+                .help("Inline images for HTML body (logo.png,chart.png=name)"),
+        )
+        .arg(
+            Arg::new("keep_recipients")
+                .long("keep-recipients")
+                .action(ArgAction::SetTrue)
+                .help("Keep original recipients for resend")
+                .requires("resend"),
+        )
+        .arg(
+            Arg::new("list_id")
+                .long("list-id")
+                .value_name("TEXT")
+                .help("List-Id header (Build notifications <builds.example.com>)"),
+        )
+        .arg(
+            Arg::new("list_unsubscribe")
+                .long("list-unsubscribe")
+                .value_name("LIST")
+                .help("List-Unsubscribe list (mailto:unsubscribe@example.com,https://example.com/unsubscribe)"),
+        )
+        .arg(
+            Arg::new("merge")
+                .long("merge")
+                .short('m')
+                .value_name("NAME")
+                .help("Mail merge file (.csv or .jsonl) with address column")
+                .conflicts_with("recipients"),
+        )
+        .arg(
+            Arg::new("no_template")
+                .long("no-template")
+                .action(ArgAction::SetTrue)
+                .help("Send title and body as is without template rendering")
+                .conflicts_with_all(["merge", "var", "vars"]),
+        )
+        .arg(
+            Arg::new("output_eml")
+                .long("output-eml")
+                .value_name("DIR")
+                .help("Write message (.eml) and envelope (.json) named by Message-ID to directory instead of sending"),
+        )
+        .arg(
+            Arg::new("precedence")
+                .long("precedence")
                 .value_name("TEXT")
-                .help("Header text"),
+                .value_parser(["bulk", "junk", "list"])
+                .help("Precedence header"),
+        )
+        .arg(
+            Arg::new("queue")
+                .long("queue")
+                .action(ArgAction::SetTrue)
+                .help("Enqueue message in the outbox queue instead of sending")
+                .conflicts_with_all(["dry_run", "output_eml"]),
         )
         .arg(
             Arg::new("recipients")
                 .long("recipients")
                 .short('p')
                 .value_name("LIST")
-                .help("Recipients list (alen@example.com,cc:bob@example.com)")
-                .required(true),
+                .help("Recipients list (alen@example.com,cc:bob@example.com,bcc:catherine@example.com)")
+                .required_unless_present_any(["merge", "resend"]),
+        )
+        .arg(
+            Arg::new("references")
+                .long("references")
+                .value_name("LIST")
+                .help("References message ids (<build-40@example.com>,<build-41@example.com>)"),
+        )
+        .arg(
+            Arg::new("reply_to")
+                .long("reply-to")
+                .value_name("LIST")
+                .help("Reply-To list (alen@example.com,bob@example.com)"),
+        )
+        .arg(
+            Arg::new("resend")
+                .long("resend")
+                .value_name("NAME")
+                .help("Resend message file (.eml) verbatim")
+                .conflicts_with("merge"),
+        )
+        .arg(
+            Arg::new("suppression")
+                .long("suppression")
+                .value_name("NAME")
+                .help("Suppression list file with one address per line"),
+        )
+        .arg(
+            Arg::new("text_body")
+                .long("text-body")
+                .value_name("TEXT_OR_NAME")
+                .help("Plain text body text or file for ALTERNATIVE"),
+        )
+        .arg(
+            Arg::new("thread")
+                .long("thread")
+                .value_name("KEY")
+                .help("Thread key (change number) to derive Message-ID and follow up the last message")
+                .conflicts_with_all(["merge", "resend"]),
+        )
+        .arg(
+            Arg::new("thread_state")
+                .long("thread-state")
+                .value_name("NAME")
+                .default_value("thread.json")
+                .help("Thread state file (.json) with the last Message-ID per thread key"),
         )
         .arg(
             Arg::new("title")
@@ -108,66 +706,73 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .value_name("TEXT")
                 .help("Title text"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("var")
+                .long("var")
+                .value_name("KEY=VALUE")
+                .action(ArgAction::Append)
+                .help("Template variable (repeatable)"),
+        )
+        .arg(
+            Arg::new("vars")
+                .long("vars")
+                .value_name("NAME")
+                .help("Template variables file (.json)"),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Verify DKIM signature of message file (.eml)")
+                .arg(
+                    Arg::new("eml")
+                        .long("eml")
+                        .value_name("NAME")
+                        .help("Message file (.eml)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("key")
+                        .long("key")
+                        .value_name("NAME")
+                        .help("Public key file (.pem or DNS TXT record)")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("queue")
+                .about("Manage outbox queue")
+                .subcommand(Command::new("flush").about("Deliver all queued messages now"))
+                .subcommand(Command::new("list").about("List queued and dead messages"))
+                .subcommand(
+                    Command::new("run")
+                        .about("Deliver due queued messages")
+                        .arg(
+                            Arg::new("daemon")
+                                .long("daemon")
+                                .action(ArgAction::SetTrue)
+                                .help("Keep delivering at the configured interval"),
+                        ),
+                )
+                .subcommand_required(true),
+        )
+        .subcommand_negates_reqs(true);
+}
 
+fn parse_parts(config: &Config, app: &ArgMatches, mail: &mut Mail) -> Result<(), Box<dyn Error>> {
     let default = "".to_string();
 
-    let mut config = Config {
-        host: "localhost".to_string(),
-        port: 25,
-        user: "".to_string(),
-        pass: "".to_string(),
-        sender: "sender@example.com".to_string(),
-        sep: ",".to_string(),
-    };
-
-    let mut mail = Mail {
-        attachment: vec![],
-        body: "".to_string(),
-        cc: vec![],
-        content_type: "".to_string(),
-        from: "".to_string(),
-        subject: "".to_string(),
-        to: vec![],
-    };
-
-    let c = app.get_one("config").unwrap_or(&default);
-    if let Ok(ret) = parse_config(c.as_str()) {
-        config = ret;
-    }
-
     let attach = app.get_one("attachment").unwrap_or(&default);
-    if let Ok(a) = parse_attachment(&config, attach.as_str()) {
-        mail.attachment = a;
-    }
+    mail.attachment = parse_attachment(config, attach.as_str())?;
 
     let body = app.get_one("body").unwrap_or(&default);
-    if let Ok(b) = parse_body(body.as_str()) {
-        mail.body = b;
-    }
+    mail.body = parse_body(body.as_str())?;
 
     let content_type = app.get_one("content_type").unwrap_or(&default);
     if let Ok(c) = parse_content_type(content_type.as_str()) {
         mail.content_type = c;
     }
 
-    let header = app.get_one("header").unwrap_or(&default);
-    mail.from = (*header.to_owned()).parse().unwrap();
-
-    let recipients = app.get_one("recipients").unwrap_or(&default);
-    let (cc, to) = parse_recipients(&config, recipients.as_str());
-
-    if cc.len() == 0 && to.len() == 0 {
-        return Err(Box::from("failed to parse recipients"));
-    }
-
-    mail.cc = cc;
-    mail.to = to;
-
-    let title = app.get_one("title").unwrap_or(&default);
-    mail.subject = (*title.to_owned()).parse().unwrap();
-
-    send_mail(&config, &mail)?;
+    let inline = app.get_one("inline").unwrap_or(&default);
+    mail.inline = parse_attachment(config, inline.as_str())?;
 
     return Ok(());
 }
@@ -178,25 +783,81 @@ fn parse_config(name: &str) -> Result<Config, Box<dyn Error>> {
 
     file.read_to_string(&mut data)?;
 
-    return serde_json::from_str(data.as_str()).map_err(|e| e.into());
+    let mut config: Config = serde_json::from_str(data.as_str())?;
+
+    // Sections left blank, as in the sample config, stay disabled
+    if config.dkim.as_ref().is_some_and(|d| d.key.is_empty()) {
+        config.dkim = None;
+    }
+    if config
+        .pgp
+        .as_ref()
+        .is_some_and(|p| p.key.is_empty() && p.keyring.is_empty())
+    {
+        config.pgp = None;
+    }
+    if config.queue.as_ref().is_some_and(|q| q.dir.is_empty()) {
+        config.queue = None;
+    }
+    if config
+        .smime
+        .as_ref()
+        .is_some_and(|s| s.cert.is_empty() && s.certs.is_empty())
+    {
+        config.smime = None;
+    }
+
+    return Ok(config);
 }
 
-fn parse_attachment(config: &Config, name: &str) -> Result<Vec<String>, Box<dyn Error>> {
-    let names = Vec::new();
+fn parse_attachment(config: &Config, name: &str) -> Result<Vec<Attach>, Box<dyn Error>> {
+    let mut buf: Vec<Attach> = Vec::new();
 
     if name.is_empty() {
-        return Ok(names);
+        return Ok(buf);
     }
 
-    let mut buf: Vec<String> = name
-        .split(&(config.sep).to_owned())
-        .map(|s| s.to_string())
-        .collect();
+    for item in name.split(&(config.sep).to_owned()) {
+        let mut params = item.split(';');
+        let file = params.next().unwrap_or_default();
+        // The display name follows the last '=', unless the whole text names a file
+        let (path, mut name) = match (check_file(file), file.rsplit_once('=')) {
+            (Ok(p), _) => (p, "".to_string()),
+            (Err(_), Some((p, n))) => (check_file(p)?, n.to_string()),
+            (Err(e), None) => return Err(e),
+        };
+        let mut content_type = "".to_string();
+
+        for param in params {
+            match param.trim().split_once('=') {
+                Some(("type", t)) => {
+                    ContentType::parse(t)?;
+                    content_type = t.to_string();
+                }
+                _ => return Err(format!("attachment parameter invalid: {}", param).into()),
+            }
+        }
+
+        if content_type.is_empty() {
+            content_type = detect_content_type(&path)?;
+        }
+
+        if name.is_empty() {
+            name = match Path::new(&path).file_name() {
+                Some(n) => n.to_string_lossy().to_string(),
+                None => return Err("attachment name invalid".into()),
+            };
+        }
 
-    for item in &mut buf {
-        *item = check_file(item)?;
+        buf.push(Attach {
+            content_type,
+            name,
+            path,
+        });
     }
 
+    rename_duplicates(&mut buf);
+
     return Ok(buf);
 }
 
@@ -219,237 +880,2999 @@ fn parse_content_type(data: &str) -> Result<String, Box<dyn Error>> {
     }
 }
 
-fn parse_recipients(config: &Config, data: &str) -> (Vec<String>, Vec<String>) {
-    let mut cc: Vec<String> = Vec::new();
-    let mut to: Vec<String> = Vec::new();
-
-    for item in data.split(&config.sep) {
-        if !item.is_empty() {
-            if item.starts_with("cc:") {
-                let buf = item.replace("cc:", "");
-                if !buf.is_empty() {
-                    cc.push(buf);
-                }
-            } else {
-                to.push(item.to_string());
-            }
-        }
-    }
-
-    cc = remove_duplicates(cc);
-    to = remove_duplicates(to);
-    cc = collect_difference(cc, to.to_owned());
+fn parse_from(data: &str) -> Result<String, Box<dyn Error>> {
+    let mailbox: Mailbox = data.parse()?;
 
-    return (cc, to);
+    return Ok(mailbox.to_string());
 }
 
-fn send_mail(config: &Config, mail: &Mail) -> Result<(), Box<dyn Error>> {
-    let content_type = ContentType::parse(mail.content_type.as_str())?;
-    let body = SinglePart::builder()
-        .header(content_type.clone())
-        .body(mail.body.clone());
-    let multi_part = MultiPart::builder().singlepart(body);
+fn parse_headers(data: &[String]) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut buf = Vec::new();
 
-    for item in &mail.attachment {
-        let body = fs::read(item)?;
-        let attachment = Attachment::new((*item).to_string()).body(body, content_type.clone());
-        multi_part.to_owned().singlepart(attachment);
-    }
+    for item in data {
+        if item.contains('\r') || item.contains('\n') {
+            return Err(format!("header invalid: {}", item.trim()).into());
+        }
 
-    let mut to = Mailboxes::new();
+        let (name, value) = match item.split_once(':') {
+            Some((n, v)) => (n.trim(), v.trim()),
+            None => return Err(format!("header invalid: {}", item).into()),
+        };
 
-    for item in mail.to.to_owned() {
-        to.push(Mailbox::new(None, item.parse()?))
-    }
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(format!("header name invalid: {}", name).into());
+        }
 
-    let mut cc = Mailboxes::new();
+        if RESERVED_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(name))
+        {
+            return Err(format!("header reserved: {}", name).into());
+        }
 
-    for item in mail.cc.to_owned() {
-        cc.push(Mailbox::new(None, item.parse()?))
+        buf.push((name.to_string(), value.to_string()));
     }
 
-    // TODO: FIXME
-    let message = Message::builder()
-        .from("alice@example.org".parse().unwrap())
-        .reply_to("bob@example.org".parse().unwrap())
-        .to("carla@example.net".parse().unwrap())
-        .subject(&mail.subject)
-        .body("test".to_owned())
-        .unwrap();
+    return Ok(buf);
+}
 
-    let creds = lettre::transport::smtp::authentication::Credentials::new(
-        config.user.clone(),
-        config.pass.clone(),
-    );
+fn parse_reply_to(config: &Config, data: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut buf = Vec::new();
 
-    let mailer = SmtpTransport::relay(&config.host)
-        .unwrap()
-        .port(config.port)
-        .credentials(creds)
-        .build();
+    for item in split_recipients(data, &config.sep) {
+        if !item.is_empty() {
+            let mailbox: Mailbox = item.parse()?;
+            buf.push(mailbox.to_string());
+        }
+    }
+
+    return Ok(remove_duplicates(buf));
+}
+
+fn parse_event(config: &Config, mut event: Event) -> Result<Event, Box<dyn Error>> {
+    if event.method == calendar::CANCEL_METHOD {
+        if event.uid.is_empty() {
+            return Err("event uid is required for CANCEL".into());
+        }
+        return Ok(event);
+    }
+
+    if event.start.is_empty() || event.end.is_empty() {
+        return Err("event start and end are required for REQUEST".into());
+    }
+
+    if event.uid.is_empty() {
+        event.uid = format!("{}@{}", mime::build_boundary()?, sender_domain(config));
+        eprintln!("uid: {}", event.uid);
+    }
+
+    return Ok(event);
+}
+
+fn parse_references(data: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut buf: Vec<String> = Vec::new();
+
+    for item in data.split([',', ' ']).filter(|i| !i.trim().is_empty()) {
+        let id = thread::parse_message_id(item)?;
+        if !buf.contains(&id) {
+            buf.push(id);
+        }
+    }
+
+    return Ok(buf);
+}
+
+fn parse_list_headers(
+    config: &Config,
+    list_id: &str,
+    unsubscribe: &str,
+    precedence: &str,
+) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut buf = Vec::new();
+
+    if !list_id.is_empty() {
+        // List-Id is a phrase followed by a dotted identifier (RFC 2919 section 2)
+        let (name, id) = match list_id.trim().rsplit_once('<') {
+            Some((n, i)) => (n.trim(), i.trim_end_matches('>')),
+            None => ("", list_id.trim()),
+        };
+        if !id.contains('.') || id.contains(|c: char| c.is_whitespace() || c == '>') {
+            return Err(format!("list id invalid: {}", list_id).into());
+        }
+        let value = match name {
+            "" => format!("<{}>", id),
+            n => format!("{} <{}>", n, id),
+        };
+        buf.push(("List-Id".to_string(), value));
+    }
+
+    if !unsubscribe.is_empty() {
+        let mut value: Vec<String> = Vec::new();
+        for item in unsubscribe.split(&config.sep).map(|i| i.trim()) {
+            let item = item.trim_start_matches('<').trim_end_matches('>');
+            if item.is_empty() {
+                continue;
+            }
+            if item.starts_with("mailto:")
+                || item.starts_with("https://")
+                || item.starts_with("http://")
+            {
+                value.push(format!("<{}>", item));
+            } else if item.contains('@') && !item.contains(':') {
+                value.push(format!("<mailto:{}>", item));
+            } else {
+                return Err(format!("list unsubscribe invalid: {}", item).into());
+            }
+        }
+        if !value.is_empty() {
+            buf.push(("List-Unsubscribe".to_string(), value.join(", ")));
+        }
+    }
+
+    if !precedence.is_empty() {
+        buf.push(("Precedence".to_string(), precedence.to_string()));
+    }
+
+    return Ok(buf);
+}
+
+fn parse_suppression(name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut buf: Vec<String> = Vec::new();
+
+    if name.is_empty() {
+        return Ok(buf);
+    }
+
+    for line in fs::read_to_string(name)?.lines() {
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            buf.push(normalize_address(line).to_lowercase());
+        }
+    }
+
+    return Ok(buf);
+}
+
+fn parse_recipients(
+    config: &Config,
+    data: &str,
+    suppression: &[String],
+) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let mut bcc: Vec<String> = Vec::new();
+    let mut cc: Vec<String> = Vec::new();
+    let mut to: Vec<String> = Vec::new();
+
+    for item in split_recipients(data, &config.sep) {
+        if !item.is_empty() {
+            if item.starts_with("bcc:") {
+                let buf = item.trim_start_matches("bcc:").trim();
+                if !buf.is_empty() {
+                    bcc.push(buf.to_string());
+                }
+            } else if item.starts_with("cc:") {
+                let buf = item.trim_start_matches("cc:").trim();
+                if !buf.is_empty() {
+                    cc.push(buf.to_string());
+                }
+            } else {
+                to.push(item);
+            }
+        }
+    }
+
+    bcc = remove_suppressed(remove_duplicates(bcc), suppression);
+    cc = remove_suppressed(remove_duplicates(cc), suppression);
+    to = remove_suppressed(remove_duplicates(to), suppression);
+    cc = collect_difference(cc, to.to_owned());
+    bcc = collect_difference(bcc, [to.to_owned(), cc.to_owned()].concat());
+
+    return (bcc, cc, to);
+}
+
+fn parse_vars(name: &str, data: &[String]) -> Result<Map<String, Value>, Box<dyn Error>> {
+    let mut buf = Map::new();
+
+    if !name.is_empty() {
+        let data = fs::read_to_string(check_file(name)?)?;
+        match serde_json::from_str(data.as_str())? {
+            Value::Object(m) => buf = m,
+            _ => return Err("vars invalid".into()),
+        }
+    }
+
+    for item in data {
+        match item.split_once('=') {
+            Some((k, v)) if !k.is_empty() => {
+                buf.insert(k.to_string(), Value::String(v.to_string()));
+            }
+            _ => return Err(format!("var invalid: {}", item).into()),
+        }
+    }
+
+    return Ok(buf);
+}
+
+fn render_mail(
+    mail: &mut Mail,
+    body: &str,
+    vars: &Map<String, Value>,
+) -> Result<(), Box<dyn Error>> {
+    let (dir, name) = match check_file(body) {
+        Ok(b) => {
+            let path = PathBuf::from(b);
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            (path.parent().unwrap_or(Path::new("")).to_path_buf(), name)
+        }
+        Err(_) => (env::current_dir()?, "body".to_string()),
+    };
+
+    let html =
+        mail.content_type == ALTERNATIVE_CONTENT_TYPE || mail.content_type == HTML_CONTENT_TYPE;
+
+    mail.subject = render_template(&dir, "title", mail.subject.as_str(), vars, false)?;
+    mail.body = render_template(&dir, name.as_str(), mail.body.as_str(), vars, html)?;
+    mail.text_body = render_template(&dir, "text_body", mail.text_body.as_str(), vars, false)?;
+
+    return Ok(());
+}
+
+fn render_template(
+    dir: &Path,
+    name: &str,
+    source: &str,
+    vars: &Map<String, Value>,
+    html: bool,
+) -> Result<String, Box<dyn Error>> {
+    let mut env = Environment::new();
+
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_loader(path_loader(dir));
+    env.set_auto_escape_callback(move |_| {
+        if html {
+            AutoEscape::Html
+        } else {
+            AutoEscape::None
+        }
+    });
+    env.add_template(name, source)?;
+
+    match env.get_template(name)?.render(vars) {
+        Ok(b) => Ok(b),
+        Err(e) => Err(format!("failed to render {}: {}", name, e).into()),
+    }
+}
+
+fn thread_mail(config: &Config, mail: &mut Mail, key: &str, state: &thread::State) {
+    let previous = state.get(key).map_or("", |p| p.as_str());
+    let ret = thread::build_thread(key, previous, &sender_domain(config));
+
+    mail.message_id = ret.message_id;
+
+    if mail.in_reply_to.is_empty() {
+        mail.in_reply_to = ret.in_reply_to;
+    }
+
+    let mut references = ret.references;
+    for item in &mail.references {
+        if !references.contains(item) {
+            references.push(item.to_string());
+        }
+    }
+    mail.references = references;
+}
+
+fn build_message(config: &Config, mail: &Mail) -> Result<Message, Box<dyn Error>> {
+    let calendar = match &mail.calendar {
+        Some(event) => Some(build_invitation(config, mail, event)?),
+        None => None,
+    };
+
+    let mut multi_part = match mail.content_type.as_str() {
+        ALTERNATIVE_CONTENT_TYPE => {
+            let mut text = mail.text_body.clone();
+            if text.is_empty() {
+                text = render_text(mail.body.as_str());
+            }
+            let mut alternative = MultiPart::alternative().singlepart(SinglePart::plain(text));
+            let html = SinglePart::html(mail.body.clone());
+            if mail.inline.is_empty() {
+                alternative = alternative.singlepart(html);
+            } else {
+                alternative = alternative.multipart(build_related(html, &mail.inline)?);
+            }
+            if let Some(c) = calendar {
+                alternative = alternative.singlepart(c);
+            }
+            MultiPart::mixed().multipart(alternative)
+        }
+        _ => {
+            let content_type = ContentType::parse(mail.content_type.as_str())?;
+            let body = SinglePart::builder()
+                .header(content_type)
+                .body(mail.body.clone());
+            if mail.content_type == HTML_CONTENT_TYPE && !mail.inline.is_empty() {
+                let related = build_related(body, &mail.inline)?;
+                match calendar {
+                    Some(c) => MultiPart::mixed()
+                        .multipart(MultiPart::alternative().multipart(related).singlepart(c)),
+                    None => MultiPart::mixed().multipart(related),
+                }
+            } else {
+                let mut multi_part = match calendar {
+                    Some(c) => MultiPart::mixed()
+                        .multipart(MultiPart::alternative().singlepart(body).singlepart(c)),
+                    None => MultiPart::mixed().singlepart(body),
+                };
+                for item in &mail.inline {
+                    let body = fs::read(&item.path)?;
+                    let attachment = Attachment::new(item.name.to_string())
+                        .body(body, ContentType::parse(item.content_type.as_str())?);
+                    multi_part = multi_part.singlepart(attachment);
+                }
+                multi_part
+            }
+        }
+    };
+
+    for item in &mail.attachment {
+        let body = build_body(fs::read(&item.path)?, item.content_type.as_str());
+        let attachment = Attachment::new(item.name.to_string())
+            .body(body, ContentType::parse(item.content_type.as_str())?);
+        multi_part = multi_part.singlepart(attachment);
+    }
+
+    let mut to = Mailboxes::new();
+
+    for item in mail.to.to_owned() {
+        to.push(item.parse()?)
+    }
+
+    let mut cc = Mailboxes::new();
+
+    for item in mail.cc.to_owned() {
+        cc.push(item.parse()?)
+    }
+
+    let mut bcc = Mailboxes::new();
+
+    for item in mail.bcc.to_owned() {
+        bcc.push(item.parse()?)
+    }
+
+    let mut from = mail.from.as_str();
+    if from.is_empty() {
+        from = config.sender.as_str();
+    }
+
+    let mut builder = Message::builder()
+        .from(from.parse()?)
+        .subject(&mail.subject);
+
+    let message_id = match mail.message_id.as_str() {
+        "" => thread::build_message_id(&sender_domain(config))?,
+        id => id.to_string(),
+    };
+    builder = builder.message_id(Some(message_id));
+
+    if !mail.in_reply_to.is_empty() {
+        builder = builder.in_reply_to(mail.in_reply_to.clone());
+    }
+
+    if !mail.references.is_empty() {
+        builder = builder.references(mail.references.join(" "));
+    }
+
+    for item in &mail.reply_to {
+        builder = builder.reply_to(item.parse()?);
+    }
+
+    for (name, value) in &mail.headers {
+        let name = HeaderName::new_from_ascii(name.to_string())?;
+        builder = builder.raw_header(HeaderValue::new(name, value.to_string()));
+    }
+
+    if to.iter().count() != 0 {
+        builder = builder.mailbox(header::To::from(to));
+    }
+
+    if cc.iter().count() != 0 {
+        builder = builder.mailbox(header::Cc::from(cc));
+    }
+
+    if bcc.iter().count() != 0 {
+        builder = builder.mailbox(header::Bcc::from(bcc));
+    }
+
+    return builder.multipart(multi_part).map_err(|e| e.into());
+}
+
+fn build_invitation(
+    config: &Config,
+    mail: &Mail,
+    event: &Event,
+) -> Result<SinglePart, Box<dyn Error>> {
+    let mut description = mail.text_body.clone();
+    if description.is_empty() {
+        description = match mail.content_type.as_str() {
+            ALTERNATIVE_CONTENT_TYPE | HTML_CONTENT_TYPE => render_text(mail.body.as_str()),
+            _ => mail.body.clone(),
+        };
+    }
+
+    let data = calendar::build_calendar(
+        event,
+        &mail.subject,
+        description.trim(),
+        &config.sender,
+        &mail.to,
+        &mail.cc,
+    )?;
+
+    let content_type = format!("text/calendar; method={}; charset=utf-8", event.method);
+
+    // Calendar lines are already folded to 75 octets, which lettre would still quote
+    let body = if data.is_ascii() {
+        Body::dangerous_pre_encoded(data.into_bytes(), ContentTransferEncoding::SevenBit)
+    } else {
+        Body::new(data)
+    };
+
+    return Ok(SinglePart::builder()
+        .header(ContentType::parse(content_type.as_str())?)
+        .body(body));
+}
+
+fn build_related(body: SinglePart, inline: &[Attach]) -> Result<MultiPart, Box<dyn Error>> {
+    let mut multi_part = MultiPart::related().singlepart(body);
+
+    for (index, item) in inline.iter().enumerate() {
+        let body = fs::read(&item.path)?;
+        let attachment =
+            Attachment::new_inline_with_name(content_id(index, item), item.name.to_string())
+                .body(body, ContentType::parse(item.content_type.as_str())?);
+        multi_part = multi_part.singlepart(attachment);
+    }
+
+    return Ok(multi_part);
+}
+
+fn build_transport(config: &Config, output: &str, dry_run: bool) -> Result<Mailer, Box<dyn Error>> {
+    if !output.is_empty() {
+        fs::create_dir_all(output)?;
+        return Ok(Mailer::File(PathBuf::from(output)));
+    }
+
+    if dry_run {
+        return Ok(Mailer::Stdout);
+    }
+
+    if let Some(t) = &config.transport {
+        match t.kind.as_str() {
+            TRANSPORT_LMTP if t.address.is_empty() => {
+                return Err("lmtp transport requires address".into())
+            }
+            TRANSPORT_LMTP => {
+                return Ok(Mailer::Lmtp(lmtp::Client {
+                    address: t.address.clone(),
+                    name: sender_domain(config),
+                }))
+            }
+            TRANSPORT_MAILDIR if t.dir.is_empty() => {
+                return Err("maildir transport requires dir".into())
+            }
+            TRANSPORT_MAILDIR => return Ok(Mailer::Maildir(t.dir.clone())),
+            // Without a command sendmail is run from PATH
+            TRANSPORT_SENDMAIL if t.command.is_empty() => {
+                return Ok(Mailer::Sendmail("sendmail".to_string()))
+            }
+            TRANSPORT_SENDMAIL => return Ok(Mailer::Sendmail(t.command.clone())),
+            TRANSPORT_SMTP => {}
+            _ => return Err(format!("transport kind invalid: {}", t.kind).into()),
+        }
+    }
+
+    let mut builder = SmtpTransport::builder_dangerous(&config.host)
+        .port(config.port)
+        .tls(build_tls(config)?);
+
+    if let Some((creds, mechanisms)) = build_auth(config)? {
+        builder = builder.credentials(creds).authentication(mechanisms);
+    }
+
+    return Ok(Mailer::Smtp(builder.build()));
+}
+
+fn build_auth(config: &Config) -> Result<Option<Login>, Box<dyn Error>> {
+    let mechanism = match &config.auth {
+        Some(a) => a.mechanism.to_uppercase(),
+        None => "".to_string(),
+    };
+
+    let mechanisms = match mechanism.as_str() {
+        "" => vec![Mechanism::Plain, Mechanism::Login],
+        AUTH_LOGIN => vec![Mechanism::Login],
+        AUTH_NONE => return Ok(None),
+        AUTH_PLAIN => vec![Mechanism::Plain],
+        AUTH_XOAUTH2 => vec![Mechanism::Xoauth2],
+        _ => return Err(format!("auth mechanism invalid: {}", mechanism).into()),
+    };
+
+    // XOAUTH2 sends the user along with the token, so an empty one never authenticates
+    if mechanism == AUTH_XOAUTH2 && config.user.is_empty() {
+        return Err("auth user is required for XOAUTH2".into());
+    }
+
+    let mut pass = config.pass.clone();
+
+    // The bearer token is fetched fresh on each run since it expires within the hour
+    if let Some(a) = config.auth.as_ref().filter(|a| !a.token_url.is_empty()) {
+        if mechanism == AUTH_XOAUTH2 {
+            let ca = config.tls.as_ref().map_or("", |t| t.ca.as_str());
+            pass = oauth2::fetch_token(a, ca)?;
+        }
+    }
+
+    if config.user.is_empty() && pass.is_empty() {
+        return Ok(None);
+    }
+
+    return Ok(Some((
+        Credentials::new(config.user.clone(), pass),
+        mechanisms,
+    )));
+}
+
+fn build_tls(config: &Config) -> Result<ClientTls, Box<dyn Error>> {
+    let tls = match &config.tls {
+        Some(t) => t,
+        None => return Ok(ClientTls::Wrapper(TlsParameters::new(config.host.clone())?)),
+    };
+
+    if tls.mode == TLS_NONE {
+        return Ok(ClientTls::None);
+    }
+
+    let mut builder = TlsParameters::builder(config.host.clone());
+
+    if !tls.ca.is_empty() {
+        // A bundle may hold several certificates while lettre takes one at a time
+        for item in X509::stack_from_pem(&fs::read(&tls.ca)?)? {
+            builder = builder.add_root_certificate(Certificate::from_der(item.to_der()?)?);
+        }
+    }
+
+    if !tls.cert.is_empty() || !tls.key.is_empty() {
+        if tls.cert.is_empty() || tls.key.is_empty() {
+            return Err("tls cert and key are required together".into());
+        }
+        let key = PKey::private_key_from_pem(&fs::read(&tls.key)?)?;
+        builder = builder.identify_with(Identity::from_pem(
+            &fs::read(&tls.cert)?,
+            &key.private_key_to_pem_pkcs8()?,
+        )?);
+    }
+
+    if tls.insecure {
+        builder = builder
+            .dangerous_accept_invalid_certs(true)
+            .dangerous_accept_invalid_hostnames(true);
+    }
+
+    let parameters = builder.build()?;
+
+    match tls.mode.as_str() {
+        TLS_IMPLICIT => Ok(ClientTls::Wrapper(parameters)),
+        TLS_STARTTLS_OPPORTUNISTIC => Ok(ClientTls::Opportunistic(parameters)),
+        TLS_STARTTLS_REQUIRED => Ok(ClientTls::Required(parameters)),
+        _ => Err(format!("tls mode invalid: {}", tls.mode).into()),
+    }
+}
+
+fn build_body(data: Vec<u8>, content_type: &str) -> Body {
+    if content_type != MESSAGE_CONTENT_TYPE {
+        return Body::new(data);
+    }
+
+    let buf = match String::from_utf8(data) {
+        Ok(b) => b,
+        Err(e) => return Body::new(e.into_bytes()),
+    };
+
+    // message/rfc822 parts may only use 7bit or 8bit (RFC 2046 section 5.2.1)
+    match Body::new_with_encoding(buf.clone(), ContentTransferEncoding::SevenBit) {
+        Ok(b) => b,
+        Err(_) => match Body::new_with_encoding(buf.clone(), ContentTransferEncoding::EightBit) {
+            Ok(b) => b,
+            Err(_) => Body::new(buf),
+        },
+    }
+}
+
+fn send_mail(config: &Config, transport: &Mailer, mail: &Mail) -> Result<(), Box<dyn Error>> {
+    match send_message(config, transport, mail) {
+        Err(e) if is_smtputf8_error(e.as_ref()) => {
+            // The server lacks SMTPUTF8 (RFC 6531) so fall back to punycode domains
+            send_message(config, transport, &encode_domains(mail)?)
+        }
+        ret => ret,
+    }
+}
+
+fn send_message(config: &Config, transport: &Mailer, mail: &Mail) -> Result<(), Box<dyn Error>> {
+    let message = build_message(config, mail)?;
+    let recipients: Vec<String> = message
+        .envelope()
+        .to()
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+
+    let mut data = message.formatted();
+    if let Some(s) = &config.smime {
+        data = smime::protect(&data, &recipients, s)?;
+    }
+
+    if let Some(p) = &config.pgp {
+        data = pgp::protect(&data, &recipients, p)?;
+    }
+
+    if let Some(d) = &config.dkim {
+        data = dkim::sign(&data, d)?;
+    }
+
+    return deliver(config, transport, message.envelope(), &data);
+}
+
+fn is_smtputf8_error(err: &(dyn Error + 'static)) -> bool {
+    match err.downcast_ref::<lettre::transport::smtp::Error>() {
+        Some(e) => e.is_client() && e.to_string().contains("SMTPUTF8"),
+        None => false,
+    }
+}
+
+fn encode_domains(mail: &Mail) -> Result<Mail, Box<dyn Error>> {
+    let encode = |data: &[String]| -> Result<Vec<String>, Box<dyn Error>> {
+        data.iter().map(|item| encode_domain(item)).collect()
+    };
+
+    let mut buf = mail.clone();
+
+    buf.bcc = encode(&mail.bcc)?;
+    buf.cc = encode(&mail.cc)?;
+    buf.reply_to = encode(&mail.reply_to)?;
+    buf.to = encode(&mail.to)?;
+
+    if !mail.from.is_empty() {
+        buf.from = encode_domain(&mail.from)?;
+    }
+
+    return Ok(buf);
+}
+
+fn encode_domain(data: &str) -> Result<String, Box<dyn Error>> {
+    let address = parse_address(data);
+
+    let (local, domain) = match address.rsplit_once('@') {
+        Some(a) => a,
+        None => return Ok(data.to_string()),
+    };
+
+    if !local.is_ascii() {
+        return Err(format!("{} requires SMTPUTF8", address).into());
+    }
+
+    let domain = idna::domain_to_ascii(domain).map_err(|e| format!("{}: {}", address, e))?;
+    let encoded = format!("{}@{}", local, domain);
+
+    match data.rfind('<') {
+        Some(s) => Ok(format!("{}<{}>", &data[..s], encoded)),
+        None => Ok(encoded),
+    }
+}
+
+fn deliver(
+    config: &Config,
+    transport: &Mailer,
+    envelope: &Envelope,
+    data: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let mut envelope = envelope.clone();
+    let mut rejected = Vec::new();
+    let ret = retry::run(config.retry.as_ref(), || {
+        let ret = send_raw(transport, &envelope, data);
+        if let Some(e) = ret.as_ref().err().and_then(|e| e.downcast_ref()) {
+            // Rejected recipients are reported once and left out of later attempts
+            for item in lmtp::rejected(e) {
+                eprintln!("{}: rejected: {} {}", item.recipient, item.code, item.text);
+                rejected.push(item.recipient.clone());
+            }
+            if lmtp::is_transient(e) {
+                envelope = lmtp::remaining(e, &envelope)?;
+            }
+        }
+        ret
+    });
+
+    // Park messages the server could not take yet in the queue for a later run
+    let ret = match (ret, &config.queue, transport) {
+        (Err(e), Some(q), Mailer::Lmtp(_) | Mailer::Sendmail(_) | Mailer::Smtp(_))
+            if retry::is_transient(e.as_ref()) =>
+        {
+            let id = queue::enqueue(q, &envelope, data)?;
+            eprintln!("{}: queued: {}", id, retry::describe(e.as_ref()));
+            Ok(())
+        }
+        (ret, _, _) => ret,
+    };
+
+    match ret {
+        Ok(_) if !rejected.is_empty() => {
+            Err(format!("lmtp rejected recipients: {}", rejected.join(", ")).into())
+        }
+        ret => ret,
+    }
+}
+
+fn send_raw(transport: &Mailer, envelope: &Envelope, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    match transport {
+        Mailer::File(d) => {
+            println!("{}", write_eml(d, envelope, data)?);
+        }
+        Mailer::Lmtp(c) => {
+            lmtp::send(c, envelope, data)?;
+        }
+        Mailer::Maildir(d) => {
+            println!("{}", maildir::deliver(d, envelope, data)?);
+        }
+        Mailer::Queue(q) => {
+            let id = queue::enqueue(q, envelope, data)?;
+            println!("{}: queued", id);
+        }
+        Mailer::Sendmail(c) => {
+            sendmail::send(c, envelope, data)?;
+        }
+        Mailer::Smtp(t) => {
+            t.send_raw(envelope, data)?;
+        }
+        Mailer::Stdout => {
+            // Keep stdout to the message alone and show who it would go to, bcc included
+            eprintln!("{}", serde_json::to_string(envelope)?);
+            println!("{}", String::from_utf8_lossy(data));
+        }
+    }
+
+    return Ok(());
+}
+
+// Files are named after the Message-ID so snapshots can find them, with a
+// counter for repeated sends of the same message
+fn write_eml(dir: &Path, envelope: &Envelope, data: &[u8]) -> Result<String, Box<dyn Error>> {
+    let id = match MessageParser::default()
+        .parse(data)
+        .and_then(|m| m.message_id().map(|i| i.to_string()))
+    {
+        Some(i) if !i.is_empty() => i,
+        _ => sha256(data)[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    };
+
+    let id: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-._@+".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let mut name = id.clone();
+    let mut index = 1;
+    while dir.join(format!("{}.eml", name)).exists() {
+        index += 1;
+        name = format!("{}-{}", id, index);
+    }
+
+    fs::write(
+        dir.join(format!("{}.json", name)),
+        serde_json::to_string(envelope)?,
+    )?;
+    fs::write(dir.join(format!("{}.eml", name)), data)?;
+
+    return Ok(format!("{}.eml", name));
+}
+
+fn run_queue(config: &Config, app: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let queue = config.queue.as_ref().ok_or("queue not configured")?;
+
+    let (force, daemon) = match app.subcommand() {
+        Some(("flush", _)) => (true, false),
+        Some(("list", _)) => {
+            for item in queue::list(queue)? {
+                let to: Vec<String> = item
+                    .entry
+                    .envelope
+                    .to()
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    item.id,
+                    item.dir,
+                    item.entry.attempts,
+                    to.join(","),
+                    item.entry.error
+                );
+            }
+            return Ok(());
+        }
+        Some(("run", sub)) => (false, sub.get_flag("daemon")),
+        _ => return Err("invalid queue command".into()),
+    };
+
+    let interval = if queue.interval > 0 {
+        queue.interval
+    } else {
+        QUEUE_INTERVAL
+    };
+
+    loop {
+        let ret = build_transport(config, "", false)
+            .and_then(|t| queue::run(queue, force, |e, d| send_raw(&t, e, d)));
+
+        // A daemon outlives failures such as an unreachable token endpoint
+        match ret {
+            Ok(ret) => println!(
+                "sent: {}, deferred: {}, dead: {}",
+                ret.sent, ret.deferred, ret.dead
+            ),
+            Err(e) if daemon => eprintln!("queue run failed: {}", e),
+            Err(e) => return Err(e),
+        }
+
+        if !daemon {
+            return Ok(());
+        }
+
+        std::thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+fn resend_mail(
+    config: &Config,
+    transport: &Mailer,
+    mail: &Mail,
+    name: &str,
+    keep: bool,
+    suppression: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(check_file(name)?)?;
+    let (recipients, _) = parse_eml(&data)?;
+
+    let mut buf = [mail.to.to_owned(), mail.cc.to_owned(), mail.bcc.to_owned()].concat();
+    if keep {
+        buf = [recipients, buf].concat();
+    }
+
+    buf = remove_suppressed(remove_duplicates(buf), suppression);
+    if buf.is_empty() {
+        return Err("failed to parse recipients".into());
+    }
+
+    let mut to = Vec::new();
+    for item in buf {
+        to.push(parse_address(&item).parse()?);
+    }
+
+    let mut from = mail.from.as_str();
+    if from.is_empty() {
+        from = config.sender.as_str();
+    }
+
+    let from: Mailbox = from.parse()?;
+    let envelope = Envelope::new(Some(from.email), to)?;
+
+    return deliver(config, transport, &envelope, &data);
+}
+
+fn forward_mail(mail: &mut Mail, name: &str) -> Result<(), Box<dyn Error>> {
+    let path = check_file(name)?;
+    let (_, subject) = parse_eml(&fs::read(&path)?)?;
+
+    if mail.subject.is_empty() {
+        mail.subject = format!("Fwd: {}", subject);
+    }
+
+    mail.attachment.push(Attach {
+        content_type: MESSAGE_CONTENT_TYPE.to_string(),
+        name: Path::new(&path)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+        path,
+    });
+
+    rename_duplicates(&mut mail.attachment);
+
+    return Ok(());
+}
+
+fn parse_eml(data: &[u8]) -> Result<(Vec<String>, String), Box<dyn Error>> {
+    let message = match MessageParser::default().parse(data) {
+        Some(m) if !m.headers().is_empty() => m,
+        _ => return Err("eml invalid".into()),
+    };
+
+    let mut buf = Vec::new();
+
+    for item in [message.to(), message.cc(), message.bcc()]
+        .into_iter()
+        .flatten()
+    {
+        for addr in item.iter() {
+            if let Some(a) = &addr.address {
+                buf.push(format_mailbox(addr.name.as_deref().unwrap_or_default(), a));
+            }
+        }
+    }
+
+    let subject = message.subject().unwrap_or_default().to_string();
+
+    return Ok((remove_duplicates(buf), subject));
+}
+
+fn send_merge(
+    config: &Config,
+    transport: &Mailer,
+    mail: &Mail,
+    body: &str,
+    vars: &Map<String, Value>,
+    name: &str,
+    suppression: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let rows = parse_merge(name)?;
+    let mut failed = 0;
+
+    for (index, row) in rows.iter().enumerate() {
+        let address = row
+            .get("address")
+            .and_then(|a| a.as_str())
+            .unwrap_or_default();
+        if suppression.contains(&normalize_address(address).to_lowercase()) {
+            println!("row {}: {}: suppressed", index + 1, address);
+            continue;
+        }
+        match merge_mail(mail, body, vars, row).and_then(|m| send_mail(config, transport, &m)) {
+            Ok(_) => println!("row {}: {}: sent", index + 1, address),
+            Err(e) => {
+                failed += 1;
+                eprintln!("row {}: {}: failed: {}", index + 1, address, e);
+            }
+        }
+    }
+
+    if failed != 0 {
+        return Err(format!("failed to send {} of {} mails", failed, rows.len()).into());
+    }
+
+    return Ok(());
+}
+
+fn parse_merge(name: &str) -> Result<Vec<Map<String, Value>>, Box<dyn Error>> {
+    let path = check_file(name)?;
+    let mut buf = Vec::new();
+
+    match Path::new(&path).extension().and_then(|e| e.to_str()) {
+        Some("csv") => {
+            let mut reader = csv::Reader::from_path(&path)?;
+            let headers = reader.headers()?.clone();
+            for record in reader.records() {
+                let mut row = Map::new();
+                for (k, v) in headers.iter().zip(record?.iter()) {
+                    row.insert(k.to_string(), Value::String(v.to_string()));
+                }
+                buf.push(row);
+            }
+        }
+        Some("json") | Some("jsonl") | Some("ndjson") => {
+            for line in fs::read_to_string(&path)?.lines() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(line)? {
+                    Value::Object(m) => buf.push(m),
+                    _ => return Err("merge row invalid".into()),
+                }
+            }
+        }
+        _ => return Err("merge file invalid".into()),
+    }
+
+    return Ok(buf);
+}
+
+fn merge_mail(
+    mail: &Mail,
+    body: &str,
+    vars: &Map<String, Value>,
+    row: &Map<String, Value>,
+) -> Result<Mail, Box<dyn Error>> {
+    let address = match row.get("address").and_then(|a| a.as_str()) {
+        Some(a) if !a.is_empty() => a,
+        _ => return Err("merge address invalid".into()),
+    };
+
+    let mut data = vars.clone();
+    data.extend(row.clone());
+
+    let name = row.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+
+    let mut buf = mail.clone();
+    buf.bcc = vec![];
+    buf.cc = vec![];
+    buf.to = vec![format_mailbox(name, address)];
+
+    render_mail(&mut buf, body, &data)?;
+
+    if buf.content_type == ALTERNATIVE_CONTENT_TYPE || buf.content_type == HTML_CONTENT_TYPE {
+        buf.body = embed_inline(buf.body.as_str(), &body_dir(body), &mut buf.inline)?;
+    }
+
+    return Ok(buf);
+}
+
+fn body_dir(name: &str) -> String {
+    let path = match check_file(name) {
+        Ok(p) => p,
+        Err(_) => return "".to_string(),
+    };
+
+    return Path::new(&path)
+        .parent()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default();
+}
+
+fn embed_inline(
+    body: &str,
+    base: &str,
+    inline: &mut Vec<Attach>,
+) -> Result<String, Box<dyn Error>> {
+    let mut buf = String::new();
+    let mut last = 0;
+
+    for item in IMAGE_SOURCE_REGEX.captures_iter(body) {
+        let source = item.get(1).unwrap();
+        let value = &source.as_str()[1..source.as_str().len() - 1];
+        if let Some(index) = find_inline(value, base, inline)? {
+            buf.push_str(&body[last..source.start()]);
+            buf.push_str(&format!("\"cid:{}\"", content_id(index, &inline[index])));
+            last = source.end();
+        }
+    }
+
+    buf.push_str(&body[last..]);
+
+    return Ok(buf);
+}
+
+fn find_inline(
+    source: &str,
+    base: &str,
+    inline: &mut Vec<Attach>,
+) -> Result<Option<usize>, Box<dyn Error>> {
+    let name = match source.strip_prefix("file:") {
+        Some(n) => n.trim_start_matches("//"),
+        None => {
+            if URL_SCHEME_REGEX.is_match(source) {
+                return Ok(None);
+            }
+            source
+        }
+    };
+
+    // Relative sources are resolved like a browser would, next to the body file
+    let name = if Path::new(name).is_relative() && !base.is_empty() {
+        Path::new(base).join(name).to_string_lossy().to_string()
+    } else {
+        name.to_string()
+    };
+
+    let path = match check_file(&name) {
+        Ok(p) => fs::canonicalize(p)?,
+        Err(_) => return Ok(None),
+    };
+
+    let content_type = detect_content_type(&name)?;
+    if !content_type.starts_with("image/") {
+        return Ok(None);
+    }
+
+    for (index, item) in inline.iter().enumerate() {
+        if fs::canonicalize(&item.path)? == path {
+            return Ok(Some(index));
+        }
+    }
+
+    inline.push(Attach {
+        content_type,
+        name: path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+        path: path.to_string_lossy().to_string(),
+    });
+
+    rename_duplicates(inline);
+
+    return Ok(Some(inline.len() - 1));
+}
+
+fn content_id(index: usize, item: &Attach) -> String {
+    let name: String = item
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    return format!("inline{}.{}", index + 1, name);
+}
+
+fn render_text(html: &str) -> String {
+    return html2text::from_read(html.as_bytes(), TEXT_WIDTH);
+}
+
+fn check_file(name: &str) -> Result<String, Box<dyn Error>> {
+    let mut buf = name.to_string();
+
+    let metadata = fs::metadata(&name);
+    match metadata {
+        Ok(md) => {
+            if md.is_file() {
+                Ok(buf)
+            } else {
+                Err(Box::try_from("file invalid").unwrap())
+            }
+        }
+        Err(_) => {
+            let root = env::current_dir()?;
+            let fullname = root.join(name);
+            match fs::metadata(&fullname) {
+                Ok(md) => {
+                    if md.is_file() {
+                        buf = fullname.to_str().unwrap().to_string();
+                        Ok(buf)
+                    } else {
+                        Err(Box::try_from("file invalid").unwrap())
+                    }
+                }
+                Err(e) => Err(Box::try_from(e).unwrap()),
+            }
+        }
+    }
+}
+
+fn detect_content_type(name: &str) -> Result<String, Box<dyn Error>> {
+    let kind = infer::get_from_path(name).map_err(|e| format!("{}: {}", name, e))?;
+    if let Some(t) = kind {
+        return Ok(t.mime_type().to_string());
+    }
+
+    let buf = match mime_guess::from_path(name).first() {
+        Some(m) => m.to_string(),
+        None => DEFAULT_CONTENT_TYPE.to_string(),
+    };
+
+    return Ok(buf);
+}
+
+fn rename_duplicates(data: &mut [Attach]) {
+    let mut names: Vec<String> = Vec::new();
+
+    for item in data.iter_mut() {
+        let mut name = item.name.to_owned();
+        let mut index = 1;
+
+        while names.contains(&name) {
+            let path = Path::new(&item.name);
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            name = match path.extension() {
+                Some(e) => format!("{} ({}).{}", stem, index, e.to_string_lossy()),
+                None => format!("{} ({})", stem, index),
+            };
+            index += 1;
+        }
+
+        names.push(name.to_owned());
+        item.name = name;
+    }
+}
+
+fn split_recipients(data: &str, sep: &str) -> Vec<String> {
+    let mut buf = Vec::new();
+    let mut item = String::new();
+    let mut escape = false;
+    let mut quote = false;
+    let mut rest = data;
+
+    while let Some(c) = rest.chars().next() {
+        if !quote && !sep.is_empty() && rest.starts_with(sep) {
+            buf.push(item.trim().to_string());
+            item.clear();
+            rest = &rest[sep.len()..];
+            continue;
+        }
+        if escape {
+            escape = false;
+        } else if quote && c == '\\' {
+            escape = true;
+        } else if c == '"' {
+            quote = !quote;
+        }
+        item.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    buf.push(item.trim().to_string());
+
+    return buf;
+}
+
+fn parse_address(data: &str) -> String {
+    match (data.rfind('<'), data.rfind('>')) {
+        (Some(s), Some(e)) if s < e => data[s + 1..e].trim().to_string(),
+        _ => data.trim().to_string(),
+    }
+}
+
+fn sender_domain(config: &Config) -> String {
+    let address = parse_address(&config.sender);
+
+    match address.rsplit_once('@') {
+        Some((_, d)) if !d.is_empty() => d.to_lowercase(),
+        _ => "localhost".to_string(),
+    }
+}
+
+fn normalize_address(data: &str) -> String {
+    let address = parse_address(data);
+
+    // Domains are case-insensitive and compared in punycode (RFC 5890)
+    match address.rsplit_once('@') {
+        Some((local, domain)) => match idna::domain_to_ascii(domain) {
+            Ok(d) => format!("{}@{}", local, d),
+            Err(_) => format!("{}@{}", local, domain.to_lowercase()),
+        },
+        None => address,
+    }
+}
+
+fn format_mailbox(name: &str, address: &str) -> String {
+    let name = name.trim();
+
+    if name.is_empty() {
+        return address.to_string();
+    }
+
+    if name.chars().any(|c| "()<>[]:;@\\,.\"".contains(c)) {
+        let buf = name.replace('\\', "\\\\").replace('"', "\\\"");
+        return format!("\"{}\" <{}>", buf, address);
+    }
+
+    return format!("{} <{}>", name, address);
+}
+
+fn remove_duplicates(data: Vec<String>) -> Vec<String> {
+    let mut buf: Vec<String> = Vec::new();
+    let mut key = Vec::new();
+
+    for item in data {
+        let address = normalize_address(&item);
+        if !key.contains(&address) {
+            key.push(address);
+            buf.push(item);
+        }
+    }
+
+    return buf;
+}
+
+fn remove_suppressed(data: Vec<String>, suppression: &[String]) -> Vec<String> {
+    let mut buf: Vec<String> = vec![];
+
+    for item in data {
+        // Opt-outs match regardless of local part case
+        if suppression.contains(&normalize_address(&item).to_lowercase()) {
+            eprintln!("suppressed: {}", item);
+        } else {
+            buf.push(item);
+        }
+    }
+
+    return buf;
+}
+
+fn collect_difference(data: Vec<String>, other: Vec<String>) -> Vec<String> {
+    let mut buf = Vec::new();
+    let mut key = Vec::new();
+
+    for item in other {
+        let address = normalize_address(&item);
+        if !key.contains(&address) {
+            key.push(address);
+        }
+    }
+
+    for item in data {
+        if !key.contains(&normalize_address(&item)) {
+            buf.push(item);
+        }
+    }
+
+    return buf;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+    use std::collections::HashSet;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn test_parse_config() {
+        assert!(parse_config("test/valid.json").is_ok());
+        assert!(parse_config("test/invalid.json").is_err());
+
+        let config = parse_config("config/sender.json").unwrap();
+        assert!(config.dkim.is_none());
+        assert!(config.pgp.is_none());
+        assert!(config.queue.is_none());
+        assert!(config.smime.is_none());
+        assert!(config.auth.is_some());
+        assert!(config.retry.is_some());
+        assert_eq!(config.tls.unwrap().mode, TLS_IMPLICIT);
+        assert_eq!(config.transport.unwrap().kind, TRANSPORT_SMTP);
+    }
+
+    #[test]
+    fn test_parse_attachment() {
+        let config = parse_config("test/valid.json").unwrap();
+
+        match parse_attachment(&config, "") {
+            Ok(b) => assert!(b.is_empty()),
+            Err(_) => assert!(false),
+        }
+
+        let name = "attach1.txt,attach2.txt";
+        assert!(parse_attachment(&config, name).is_err());
+
+        let name = "test/attach1.txt,test/attach2.txt";
+        match parse_attachment(&config, name) {
+            Ok(b) => assert_eq!(b.len(), 2),
+            Err(_) => assert!(false),
+        }
+
+        let name = "test/attach1.txt;type=application/x-foo,test/attach3.png";
+        match parse_attachment(&config, name) {
+            Ok(b) => {
+                assert_eq!(b.len(), 2);
+                assert_eq!(b[0].content_type, "application/x-foo");
+                assert_eq!(b[1].content_type, "image/png");
+            }
+            Err(_) => assert!(false),
+        }
+
+        let name = "test/attach1.txt=report.txt,test/attach2.txt=报告.txt;type=text/plain";
+        match parse_attachment(&config, name) {
+            Ok(b) => {
+                assert_eq!(b.len(), 2);
+                assert_eq!(b[0].name, "report.txt");
+                assert_eq!(b[1].name, "报告.txt");
+                assert_eq!(b[1].content_type, "text/plain");
+            }
+            Err(_) => assert!(false),
+        }
+
+        let name = "test/build=42.txt,test/build=42.txt=build.log";
+        match parse_attachment(&config, name) {
+            Ok(b) => {
+                assert_eq!(b.len(), 2);
+                assert_eq!(b[0].name, "build=42.txt");
+                assert_eq!(b[1].name, "build.log");
+                assert!(b[1].path.ends_with("test/build=42.txt"));
+            }
+            Err(_) => assert!(false),
+        }
+
+        let name = "test/attach1.txt,test/attach1.txt,test/attach2.txt=attach1.txt";
+        match parse_attachment(&config, name) {
+            Ok(b) => {
+                assert_eq!(b.len(), 3);
+                assert!(b[0].path.ends_with("attach1.txt"));
+                assert_eq!(b[0].name, "attach1.txt");
+                assert_eq!(b[1].name, "attach1 (1).txt");
+                assert_eq!(b[2].name, "attach1 (2).txt");
+            }
+            Err(_) => assert!(false),
+        }
+
+        let name = "test/attach1.txt;type=foo";
+        assert!(parse_attachment(&config, name).is_err());
+
+        let name = "test/attach1.txt;name=foo";
+        assert!(parse_attachment(&config, name).is_err());
+    }
+
+    #[test]
+    fn test_parse_parts() {
+        let config = parse_config("test/valid.json").unwrap();
+        let parse = |args: &[&str]| {
+            let app = build_command()
+                .try_get_matches_from(
+                    [&["sender", "--recipients", "alen@example.com"], args].concat(),
+                )
+                .unwrap();
+            let mut mail = Mail::default();
+            parse_parts(&config, &app, &mut mail).map(|_| mail)
+        };
+
+        let mail = parse(&["--attachment", "test/attach1.txt;type=text/plain"]).unwrap();
+        assert_eq!(mail.attachment.len(), 1);
+
+        // A bad attachment spec stops the run instead of sending without it
+        assert!(parse(&["--attachment", "test/attach1.txt;type=foo"]).is_err());
+        assert!(parse(&["--attachment", "test/attach1.txt;name=foo"]).is_err());
+        assert!(parse(&["--attachment", "test/missing.txt"]).is_err());
+        assert!(parse(&["--attachment", "test/report"]).is_err());
+
+        // As would a missing inline image or a body file that cannot be read
+        let mail = parse(&["--body", "test/body.txt", "--inline", "test/attach3.png"]).unwrap();
+        assert!(!mail.body.is_empty());
+        assert_eq!(mail.inline.len(), 1);
+        assert!(parse(&["--inline", "test/missing.png"]).is_err());
+        assert!(parse(&["--body", "test/attach3.png"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_body() {
+        assert!(parse_body("").is_ok());
+
+        match parse_body("body") {
+            Ok(b) => assert_eq!(b, "body"),
+            Err(_) => assert!(false),
+        }
+
+        match parse_body("body.txt") {
+            Ok(b) => assert_eq!(b, "body.txt"),
+            Err(_) => assert!(false),
+        }
+
+        assert!(parse_body("test/body.txt").is_ok());
+    }
+
+    #[test]
+    fn test_parse_content_type() {
+        assert!(parse_content_type("FOO").is_err());
+
+        if let Ok(b) = parse_content_type("ALTERNATIVE") {
+            assert_eq!(b, "multipart/alternative".to_string());
+        }
+
+        if let Ok(b) = parse_content_type("HTML") {
+            assert_eq!(b, "text/html".to_string());
+        }
+
+        if let Ok(b) = parse_content_type("PLAIN_TEXT") {
+            assert_eq!(b, "text/plain".to_string());
+        }
+    }
+
+    #[test]
+    fn test_parse_from() {
+        assert_eq!(parse_from("alen@example.com").unwrap(), "alen@example.com");
+        assert_eq!(
+            parse_from("Alen Smith <alen@example.com>").unwrap(),
+            "Alen Smith <alen@example.com>"
+        );
+        assert!(parse_from("").is_err());
+        assert!(parse_from("alen@example.com\r\nBcc: bob@example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        match parse_headers(&["X-Build-Id: 1234".to_string(), "X-Empty:".to_string()]) {
+            Ok(b) => {
+                assert_eq!(b.len(), 2);
+                assert_eq!(b[0], ("X-Build-Id".to_string(), "1234".to_string()));
+                assert_eq!(b[1], ("X-Empty".to_string(), "".to_string()));
+            }
+            Err(_) => assert!(false),
+        }
+
+        assert!(parse_headers(&["X-Build-Id".to_string()]).is_err());
+        assert!(parse_headers(&[": 1234".to_string()]).is_err());
+        assert!(parse_headers(&["X Build: 1234".to_string()]).is_err());
+        assert!(parse_headers(&["X-Build-Id: 1\r\nBcc: bob@example.com".to_string()]).is_err());
+        assert!(parse_headers(&["X-Build-Id: 1\nBcc: bob@example.com".to_string()]).is_err());
+        assert!(parse_headers(&["bcc: bob@example.com".to_string()]).is_err());
+        assert!(parse_headers(&["Content-Type: text/html".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_reply_to() {
+        let config = parse_config("test/valid.json").unwrap();
+
+        match parse_reply_to(&config, "") {
+            Ok(b) => assert!(b.is_empty()),
+            Err(_) => assert!(false),
+        }
+
+        match parse_reply_to(
+            &config,
+            "\"Smith, Alen\" <alen@example.com>,bob@example.com,",
+        ) {
+            Ok(b) => {
+                assert_eq!(b.len(), 2);
+                assert_eq!(b[1], "bob@example.com");
+            }
+            Err(_) => assert!(false),
+        }
+
+        assert!(parse_reply_to(&config, "alen").is_err());
+    }
+
+    #[test]
+    fn test_parse_recipients() {
+        let config = parse_config("test/valid.json").unwrap();
+
+        let recipients = "alen@example.com";
+        let (bcc, cc, to) = parse_recipients(&config, recipients, &[]);
+        assert!(bcc.is_empty());
+        assert!(cc.is_empty());
+        assert_eq!(to.len(), 1);
+        assert_eq!(to[0], "alen@example.com");
+
+        let recipients = "alen@example.com,cc:,cc:bob@example.com,";
+        let (bcc, cc, to) = parse_recipients(&config, recipients, &[]);
+        assert!(bcc.is_empty());
+        assert_eq!(cc.len(), 1);
+        assert_eq!(cc[0], "bob@example.com");
+        assert_eq!(to.len(), 1);
+        assert_eq!(to[0], "alen@example.com");
+
+        let recipients = "alen@example.com,alen@example.com,cc:bob@example.com,cc:bob@example.com,";
+        let (bcc, cc, to) = parse_recipients(&config, recipients, &[]);
+        assert!(bcc.is_empty());
+        assert_eq!(cc.len(), 1);
+        assert_eq!(cc[0], "bob@example.com");
+        assert_eq!(to.len(), 1);
+        assert_eq!(to[0], "alen@example.com");
+
+        let recipients = "alen@example.com,bob@example.com,cc:bob@example.com,cc:bob@example.com,";
+        let (bcc, cc, to) = parse_recipients(&config, recipients, &[]);
+        assert!(bcc.is_empty());
+        assert!(cc.is_empty());
+        assert_eq!(to.len(), 2);
+        assert_eq!(to[0], "alen@example.com");
+        assert_eq!(to[1], "bob@example.com");
+
+        let recipients = "alen@example.com,bcc:,bcc:alen@example.com,cc:bob@example.com,bcc:bob@example.com,bcc:catherine@example.com,bcc:catherine@example.com";
+        let (bcc, cc, to) = parse_recipients(&config, recipients, &[]);
+        assert_eq!(bcc.len(), 1);
+        assert_eq!(bcc[0], "catherine@example.com");
+        assert_eq!(cc.len(), 1);
+        assert_eq!(cc[0], "bob@example.com");
+        assert_eq!(to.len(), 1);
+        assert_eq!(to[0], "alen@example.com");
+
+        let recipients = "\"Smith, Alen\" <alen@example.com>,cc:Bob <bob@example.com>,cc:bob@example.com,bcc:alen@example.com";
+        let (bcc, cc, to) = parse_recipients(&config, recipients, &[]);
+        assert!(bcc.is_empty());
+        assert_eq!(cc.len(), 1);
+        assert_eq!(cc[0], "Bob <bob@example.com>");
+        assert_eq!(to.len(), 1);
+        assert_eq!(to[0], "\"Smith, Alen\" <alen@example.com>");
+
+        let suppression = parse_suppression("test/suppression.txt").unwrap();
+        let recipients =
+            "alen@example.com,bob@example.com,cc:Bob <bob@example.com>,bcc:catherine@example.com";
+        let (bcc, cc, to) = parse_recipients(&config, recipients, &suppression);
+        assert!(bcc.is_empty());
+        assert!(cc.is_empty());
+        assert_eq!(to, vec!["alen@example.com".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_suppression() {
+        assert!(parse_suppression("").unwrap().is_empty());
+        assert_eq!(
+            parse_suppression("test/suppression.txt").unwrap(),
+            vec![
+                "bob@example.com".to_string(),
+                "catherine@example.com".to_string()
+            ]
+        );
+        assert!(parse_suppression("test/missing.txt").is_err());
+    }
+
+    #[test]
+    fn test_parse_list_headers() {
+        let config = parse_config("test/valid.json").unwrap();
+
+        assert!(parse_list_headers(&config, "", "", "").unwrap().is_empty());
+
+        let ret = parse_list_headers(
+            &config,
+            "Build notifications <builds.example.com>",
+            "unsubscribe@example.com,https://example.com/unsubscribe?id=42",
+            "bulk",
+        )
+        .unwrap();
+        assert_eq!(
+            ret,
+            vec![
+                (
+                    "List-Id".to_string(),
+                    "Build notifications <builds.example.com>".to_string()
+                ),
+                (
+                    "List-Unsubscribe".to_string(),
+                    "<mailto:unsubscribe@example.com>, <https://example.com/unsubscribe?id=42>"
+                        .to_string()
+                ),
+                ("Precedence".to_string(), "bulk".to_string()),
+            ]
+        );
+
+        let ret = parse_list_headers(&config, "builds.example.com", "", "").unwrap();
+        assert_eq!(ret[0].1, "<builds.example.com>");
+
+        assert!(parse_list_headers(&config, "builds", "", "").is_err());
+        assert!(parse_list_headers(&config, "", "ftp://example.com", "").is_err());
+    }
+
+    #[test]
+    fn test_parse_vars() {
+        match parse_vars("", &[]) {
+            Ok(b) => assert!(b.is_empty()),
+            Err(_) => assert!(false),
+        }
+
+        match parse_vars(
+            "test/vars.json",
+            &["name=bob".to_string(), "id=1=2".to_string()],
+        ) {
+            Ok(b) => {
+                assert_eq!(b["name"], "bob");
+                assert_eq!(b["id"], "1=2");
+                assert!(b["items"].is_array());
+            }
+            Err(_) => assert!(false),
+        }
+
+        assert!(parse_vars("test/invalid.json", &[]).is_err());
+        assert!(parse_vars("", &["name".to_string()]).is_err());
+        assert!(parse_vars("", &["=bob".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_render_mail() {
+        let vars = parse_vars("test/vars.json", &[]).unwrap();
+
+        let mut mail = Mail {
+            body: parse_body("test/body.html").unwrap(),
+            content_type: "text/html".to_string(),
+            subject: "Build {{ id }} {% if passed %}passed{% else %}failed{% endif %}".to_string(),
+            text_body: "{{ name }}".to_string(),
+            ..Default::default()
+        };
+
+        render_mail(&mut mail, "test/body.html", &vars).unwrap();
+        assert_eq!(mail.subject, "Build 42 passed");
+        assert!(mail.body.contains("<h1>Hello Alen &lt;dev&gt;</h1>"));
+        assert!(mail.body.contains("<li>foo</li><li>bar</li>"));
+        assert!(mail.body.contains("<p>Regards</p>"));
+        assert_eq!(mail.text_body, "Alen <dev>");
+
+        let mut mail = Mail {
+            body: "line\n{{ missing }}".to_string(),
+            content_type: "text/plain".to_string(),
+            ..Default::default()
+        };
+
+        match render_mail(&mut mail, "", &vars) {
+            Ok(_) => assert!(false),
+            Err(e) => {
+                let buf = e.to_string();
+                assert!(buf.contains("undefined"));
+                assert!(buf.contains("body:2"));
+            }
+        }
+
+        let mut mail = Mail {
+            body: "plain body".to_string(),
+            subject: "Hi {{ name }}".to_string(),
+            ..Default::default()
+        };
+        assert!(render_mail(&mut mail, "", &Map::new()).is_err());
+        mail.subject = "Hi".to_string();
+        render_mail(&mut mail, "", &Map::new()).unwrap();
+        assert_eq!(mail.body, "plain body");
+    }
+
+    #[test]
+    fn test_parse_merge() {
+        match parse_merge("test/merge.csv") {
+            Ok(b) => {
+                assert_eq!(b.len(), 2);
+                assert_eq!(b[0]["address"], "alen@example.com");
+                assert_eq!(b[1]["name"], "Bob");
+                assert_eq!(b[1]["build"], "43");
+            }
+            Err(_) => assert!(false),
+        }
+
+        match parse_merge("test/merge.jsonl") {
+            Ok(b) => {
+                assert_eq!(b.len(), 2);
+                assert_eq!(b[0]["address"], "alen@example.com");
+                assert_eq!(b[1]["build"], 43);
+            }
+            Err(_) => assert!(false),
+        }
+
+        assert!(parse_merge("test/body.txt").is_err());
+        assert!(parse_merge("test/valid.json").is_err());
+    }
+
+    #[test]
+    fn test_merge_mail() {
+        let vars = parse_vars("", &["project=rsmail".to_string()]).unwrap();
+        let rows = parse_merge("test/merge.csv").unwrap();
+
+        let mail = Mail {
+            body: "Hi {{ name }}, {{ project }} build {{ build }} is out".to_string(),
+            cc: vec!["carla@example.com".to_string()],
+            content_type: "text/plain".to_string(),
+            subject: "{{ project }} {{ build }}".to_string(),
+            ..Default::default()
+        };
+
+        let buf = merge_mail(&mail, "", &vars, &rows[1]).unwrap();
+        assert!(buf.cc.is_empty());
+        assert_eq!(buf.to, vec!["Bob <bob@example.com>".to_string()]);
+        assert_eq!(buf.subject, "rsmail 43");
+        assert_eq!(buf.body, "Hi Bob, rsmail build 43 is out");
+        assert_eq!(mail.subject, "{{ project }} {{ build }}");
+
+        let row = Map::new();
+        assert!(merge_mail(&mail, "", &vars, &row).is_err());
+    }
+
+    #[test]
+    fn test_send_merge() {
+        let config = parse_config("test/valid.json").unwrap();
+        let vars = parse_vars("", &["project=rsmail".to_string()]).unwrap();
+        let suppression = parse_suppression("test/suppression.txt").unwrap();
+
+        let dir = env::temp_dir().join(format!("sender-{}-send-merge", std::process::id()));
+        let transport = build_transport(&config, dir.to_str().unwrap(), false).unwrap();
+
+        let mail = Mail {
+            body: "Hi {{ name }}".to_string(),
+            content_type: "text/plain".to_string(),
+            subject: "{{ project }} {{ build }}".to_string(),
+            ..Default::default()
+        };
+
+        assert!(send_merge(
+            &config,
+            &transport,
+            &mail,
+            "",
+            &vars,
+            "test/merge.csv",
+            &suppression
+        )
+        .is_ok());
+
+        // bob@example.com is suppressed so only the first row is sent
+        let mut eml = vec![];
+        for item in fs::read_dir(&dir).unwrap() {
+            let path = item.unwrap().path();
+            if path.extension().and_then(|e| e.to_str()) == Some("eml") {
+                eml.push(fs::read_to_string(&path).unwrap());
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(eml.len(), 1);
+        assert!(eml[0].contains("alen@example.com"));
+        assert!(!eml[0].contains("bob@example.com"));
+    }
+
+    #[test]
+    fn test_build_message() {
+        let config = parse_config("test/valid.json").unwrap();
+
+        let mail = Mail {
+            attachment: vec![
+                Attach {
+                    content_type: "text/plain".to_string(),
+                    name: "报告.txt".to_string(),
+                    path: "test/attach1.txt".to_string(),
+                },
+                Attach {
+                    content_type: "image/png".to_string(),
+                    name: "attach3.png".to_string(),
+                    path: "test/attach3.png".to_string(),
+                },
+            ],
+            bcc: vec!["carla@example.com".to_string()],
+            body: "body".to_string(),
+            cc: vec!["bob@example.com".to_string()],
+            content_type: "text/plain".to_string(),
+            headers: vec![("X-Build-Id".to_string(), "1234".to_string())],
+            reply_to: vec![
+                "alen@example.com".to_string(),
+                "bob@example.com".to_string(),
+            ],
+            subject: "title".to_string(),
+            to: vec!["\"Smith, Alen\" <alen@example.com>".to_string()],
+            ..Default::default()
+        };
+
+        let message = build_message(&config, &mail).unwrap();
+        let buf = String::from_utf8(message.formatted()).unwrap();
+
+        assert!(buf.contains("From: sender@example.com\r\n"));
+        assert!(buf.contains("Reply-To: alen@example.com, bob@example.com\r\n"));
+        assert!(buf.contains("X-Build-Id: 1234\r\n"));
+        assert!(buf.contains("To: =?utf-8?b?U21pdGgsIEFsZW4=?= <alen@example.com>\r\n"));
+        assert!(buf.contains("Cc: bob@example.com\r\n"));
+        assert!(buf.contains("Subject: title\r\n"));
+        assert!(buf.contains("@example.com>\r\n"));
+        assert!(!buf.contains("In-Reply-To:"));
+        assert!(buf.contains("Content-Type: multipart/mixed;"));
+        assert_eq!(buf.matches("Content-Type: text/plain").count(), 2);
+        assert_eq!(buf.matches("Content-Type: image/png").count(), 1);
+        assert_eq!(buf.matches("Content-Disposition: attachment;").count(), 2);
+        assert!(buf.contains("filename*0*=utf-8''%E6%8A%A5%E5%91%8A.txt"));
+        assert!(buf.contains("filename=\"attach3.png\""));
+        assert!(!buf.contains("test/attach3.png"));
+        assert!(buf.contains("\r\n\r\nbody\r\n"));
+
+        assert!(!buf.contains("Bcc:"));
+        assert!(!buf.contains("carla@example.com"));
+
+        let envelope = message.envelope();
+        assert_eq!(envelope.to().len(), 3);
+        assert!(envelope
+            .to()
+            .contains(&"carla@example.com".parse().unwrap()));
+
+        let mail = Mail {
+            body: "body".to_string(),
+            cc: vec!["bob@example.com".to_string()],
+            content_type: "text/plain".to_string(),
+            subject: "title".to_string(),
+            ..Default::default()
+        };
 
-    match mailer.send(&message) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Box::try_from(e).unwrap()),
+        let mut mail = mail;
+        mail.from = "Build Bot <bot@example.com>".to_string();
+
+        let message = build_message(&config, &mail).unwrap();
+        let buf = String::from_utf8(message.formatted()).unwrap();
+        assert!(buf.contains("From: \"Build Bot\" <bot@example.com>\r\n"));
+        assert!(!buf.contains("Reply-To:"));
+        assert!(!buf.contains("To: "));
+        assert!(buf.contains("Cc: bob@example.com\r\n"));
+
+        let mail = Mail {
+            body: "<p>body</p>".to_string(),
+            content_type: "multipart/alternative".to_string(),
+            subject: "title".to_string(),
+            text_body: "text".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
+
+        let message = build_message(&config, &mail).unwrap();
+        let buf = String::from_utf8(message.formatted()).unwrap();
+        assert!(buf.contains("Content-Type: multipart/mixed;"));
+        assert!(buf.contains("Content-Type: multipart/alternative;"));
+        assert!(buf.find("multipart/mixed").unwrap() < buf.find("multipart/alternative").unwrap());
+        assert!(buf.find("text/plain").unwrap() < buf.find("text/html").unwrap());
+        assert!(buf.contains("\r\n\r\ntext\r\n"));
+        assert!(buf.contains("\r\n\r\n<p>body</p>\r\n"));
+
+        let mut inline = vec![];
+        let body = embed_inline("<img src=\"test/attach3.png\">", "", &mut inline).unwrap();
+
+        let mail = Mail {
+            body,
+            content_type: "multipart/alternative".to_string(),
+            inline,
+            subject: "title".to_string(),
+            text_body: "text".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
+
+        let message = build_message(&config, &mail).unwrap();
+        let buf = String::from_utf8(message.formatted()).unwrap();
+        assert!(
+            buf.find("multipart/alternative").unwrap() < buf.find("multipart/related").unwrap()
+        );
+        assert!(buf.find("multipart/related").unwrap() < buf.find("text/html").unwrap());
+        assert!(buf.find("text/html").unwrap() < buf.find("image/png").unwrap());
+        assert!(buf.contains("<img src=\"cid:inline1.attach3.png\">"));
+        assert!(buf.contains("Content-ID: <inline1.attach3.png>\r\n"));
+        assert!(buf.contains("Content-Disposition: inline; filename=\"attach3.png\""));
     }
-}
 
-fn check_file(name: &str) -> Result<String, Box<dyn Error>> {
-    let mut buf = name.to_string();
+    #[test]
+    fn test_thread_mail() {
+        let config = parse_config("test/valid.json").unwrap();
 
-    let metadata = fs::metadata(&name);
-    match metadata {
-        Ok(md) => {
-            if md.is_file() {
-                Ok(buf)
-            } else {
-                Err(Box::try_from("file invalid").unwrap())
+        let mut mail = Mail {
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            references: parse_references("<build-40@example.com>").unwrap(),
+            subject: "Build 42".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
+
+        let mut state = crate::thread::State::new();
+        let mut root = mail.clone();
+        thread_mail(&config, &mut root, "change-42", &state);
+        assert!(root.message_id.ends_with("@example.com>"));
+        assert!(root.in_reply_to.is_empty());
+        assert_eq!(root.references, vec!["<build-40@example.com>".to_string()]);
+
+        state.insert("change-42".to_string(), root.message_id.clone());
+        thread_mail(&config, &mut mail, "change-42", &state);
+        assert_ne!(mail.message_id, root.message_id);
+        assert_eq!(mail.in_reply_to, root.message_id);
+        assert_eq!(
+            mail.references,
+            vec![
+                root.message_id.clone(),
+                "<build-40@example.com>".to_string()
+            ]
+        );
+
+        let buf = String::from_utf8(build_message(&config, &mail).unwrap().formatted()).unwrap();
+        assert!(buf.contains(&format!("Message-ID: {}\r\n", mail.message_id)));
+        assert!(buf.contains(&format!("In-Reply-To: {}\r\n", root.message_id)));
+        assert!(buf.replace("\r\n ", " ").contains(&format!(
+            "References: {} <build-40@example.com>\r\n",
+            root.message_id
+        )));
+    }
+
+    #[test]
+    fn test_parse_references() {
+        let ret = parse_references("<build-40@example.com>, build-41@example.com").unwrap();
+        assert_eq!(
+            ret,
+            vec![
+                "<build-40@example.com>".to_string(),
+                "<build-41@example.com>".to_string()
+            ]
+        );
+        assert!(parse_references("").unwrap().is_empty());
+        assert!(parse_references("build-41").is_err());
+    }
+
+    #[test]
+    fn test_build_message_calendar() {
+        let config = parse_config("test/valid.json").unwrap();
+
+        let event = Event {
+            end: "2026-10-20T16:00+08:00".to_string(),
+            location: "Room 1".to_string(),
+            method: calendar::REQUEST_METHOD.to_string(),
+            sequence: 0,
+            start: "2026-10-20T15:00+08:00".to_string(),
+            timezone: "Asia/Shanghai".to_string(),
+            uid: "1234@example.com".to_string(),
+        };
+
+        let mut mail = Mail {
+            body: "<p>Release review</p>".to_string(),
+            calendar: Some(event.clone()),
+            cc: vec!["bob@example.com".to_string()],
+            content_type: "text/html".to_string(),
+            subject: "Review".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
+
+        let buf = String::from_utf8(build_message(&config, &mail).unwrap().formatted()).unwrap();
+        assert!(buf.contains("Content-Type: multipart/alternative;"));
+        assert!(buf.contains("Content-Type: text/html"));
+        assert!(buf.contains(
+            "Content-Type: text/calendar; method=REQUEST; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 7bit\r\n\r\nBEGIN:VCALENDAR\r\n"
+        ));
+        assert!(buf.contains("DESCRIPTION:Release review\r\n"));
+
+        mail.content_type = ALTERNATIVE_CONTENT_TYPE.to_string();
+        mail.calendar = Some(Event {
+            method: calendar::CANCEL_METHOD.to_string(),
+            sequence: 1,
+            ..event
+        });
+
+        let buf = String::from_utf8(build_message(&config, &mail).unwrap().formatted()).unwrap();
+        assert_eq!(
+            buf.matches("Content-Type: multipart/alternative;").count(),
+            1
+        );
+        assert!(buf.contains("Content-Type: text/plain"));
+        assert!(buf.contains("Content-Type: text/calendar; method=CANCEL; charset=utf-8"));
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let config = parse_config("test/valid.json").unwrap();
+
+        let event = Event {
+            end: "".to_string(),
+            location: "".to_string(),
+            method: calendar::CANCEL_METHOD.to_string(),
+            sequence: 1,
+            start: "".to_string(),
+            timezone: "".to_string(),
+            uid: "".to_string(),
+        };
+
+        assert!(parse_event(&config, event.clone()).is_err());
+
+        let ret = parse_event(
+            &config,
+            Event {
+                uid: "1234@example.com".to_string(),
+                ..event.clone()
+            },
+        );
+        assert!(ret.is_ok());
+
+        let request = Event {
+            method: calendar::REQUEST_METHOD.to_string(),
+            ..event
+        };
+        assert!(parse_event(&config, request.clone()).is_err());
+
+        let ret = parse_event(
+            &config,
+            Event {
+                end: "2026-10-20T16:00Z".to_string(),
+                start: "2026-10-20T15:00Z".to_string(),
+                ..request
+            },
+        )
+        .unwrap();
+        assert!(ret.uid.ends_with("@example.com"));
+    }
+
+    #[test]
+    fn test_parse_eml() {
+        let data = fs::read("test/message.eml").unwrap();
+
+        match parse_eml(&data) {
+            Ok((recipients, subject)) => {
+                assert_eq!(recipients.len(), 3);
+                assert_eq!(recipients[0], "\"Smith, Alen\" <alen@example.com>");
+                assert_eq!(recipients[1], "bob@example.com");
+                assert_eq!(recipients[2], "Carla <carla@example.com>");
+                assert_eq!(subject, "Build 42 报告");
             }
+            Err(_) => assert!(false),
         }
-        Err(_) => {
-            let root = env::current_dir()?;
-            let fullname = root.join(name);
-            match fs::metadata(&fullname) {
-                Ok(md) => {
-                    if md.is_file() {
-                        buf = fullname.to_str().unwrap().to_string();
-                        Ok(buf)
-                    } else {
-                        Err(Box::try_from("file invalid").unwrap())
-                    }
+
+        assert!(parse_eml(b"").is_err());
+    }
+
+    #[test]
+    fn test_forward_mail() {
+        let mut mail = Mail {
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            to: vec!["david@example.com".to_string()],
+            ..Default::default()
+        };
+
+        forward_mail(&mut mail, "test/message.eml").unwrap();
+        assert_eq!(mail.subject, "Fwd: Build 42 报告");
+        assert_eq!(mail.attachment.len(), 1);
+        assert_eq!(mail.attachment[0].content_type, "message/rfc822");
+        assert_eq!(mail.attachment[0].name, "message.eml");
+
+        let config = parse_config("test/valid.json").unwrap();
+        let message = build_message(&config, &mail).unwrap();
+        let buf = String::from_utf8(message.formatted()).unwrap();
+        assert!(buf.contains("Content-Type: message/rfc822\r\n"));
+        assert!(buf.contains("Content-Transfer-Encoding: 8bit\r\n"));
+        assert!(buf.contains("Message-ID: <build-42@example.com>\r\n"));
+
+        assert!(forward_mail(&mut mail, "test/missing.eml").is_err());
+    }
+
+    #[test]
+    fn test_resend_mail() {
+        let config = parse_config("test/valid.json").unwrap();
+
+        let dir = env::temp_dir().join(format!("sender-{}-resend-mail", std::process::id()));
+        let transport = build_transport(&config, dir.to_str().unwrap(), false).unwrap();
+
+        let mut mail = Mail {
+            cc: vec!["bob@example.com".to_string()],
+            to: vec!["david@example.com".to_string()],
+            ..Default::default()
+        };
+
+        let suppression = parse_suppression("test/suppression.txt").unwrap();
+        assert!(resend_mail(&config, &transport, &mail, "test/message.eml", false, &[]).is_ok());
+        assert!(resend_mail(
+            &config,
+            &transport,
+            &mail,
+            "test/message.eml",
+            true,
+            &suppression
+        )
+        .is_ok());
+        assert!(dir.join("build-42@example.com.eml").is_file());
+        assert!(dir.join("build-42@example.com-2.json").is_file());
+
+        mail.cc = vec![];
+        mail.to = vec![];
+        assert!(resend_mail(&config, &transport, &mail, "test/message.eml", false, &[]).is_err());
+
+        let data = fs::read("test/message.eml").unwrap();
+        let mut envelopes = vec![];
+
+        for item in fs::read_dir(&dir).unwrap() {
+            let path = item.unwrap().path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("eml") => assert_eq!(fs::read(&path).unwrap(), data),
+                Some("json") => {
+                    let buf: Value =
+                        serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+                    envelopes.push(buf["forward_path"].as_array().unwrap().len());
                 }
-                Err(e) => Err(Box::try_from(e).unwrap()),
+                _ => assert!(false),
             }
         }
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The kept bob@example.com is suppressed
+        envelopes.sort();
+        assert_eq!(envelopes, vec![2, 3]);
     }
-}
 
-fn remove_duplicates(data: Vec<String>) -> Vec<String> {
-    let mut buf = Vec::new();
+    #[test]
+    fn test_send_mail() {
+        let config = parse_config("test/valid.json").unwrap();
 
-    for item in data {
-        if !buf.contains(&item) {
-            buf.push(item);
+        let dir = env::temp_dir().join(format!("sender-{}-send-mail", std::process::id()));
+        let transport = build_transport(&config, dir.to_str().unwrap(), false).unwrap();
+
+        let mail = Mail {
+            bcc: vec!["carla@example.com".to_string()],
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            message_id: "<build-42.1@example.com>".to_string(),
+            subject: "title".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
+
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+
+        let mut eml = vec![];
+        let mut json = vec![];
+
+        for item in fs::read_dir(&dir).unwrap() {
+            let path = item.unwrap().path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("eml") => eml.push(fs::read_to_string(&path).unwrap()),
+                Some("json") => json.push(fs::read_to_string(&path).unwrap()),
+                _ => assert!(false),
+            }
         }
+
+        assert!(dir.join("build-42.1@example.com.eml").is_file());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(eml.len(), 1);
+        assert!(eml[0].contains("Message-ID: <build-42.1@example.com>\r\n"));
+        assert!(eml[0].contains("To: alen@example.com\r\n"));
+        assert!(eml[0].contains("Subject: title\r\n"));
+        assert!(!eml[0].contains("carla@example.com"));
+
+        assert_eq!(json.len(), 1);
+        let envelope: Value = serde_json::from_str(&json[0]).unwrap();
+        assert_eq!(envelope["forward_path"][0], "alen@example.com");
+        assert_eq!(envelope["forward_path"][1], "carla@example.com");
+        assert_eq!(envelope["reverse_path"], "sender@example.com");
+
+        let transport = build_transport(&config, "", true).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
     }
 
-    return buf;
-}
+    fn start_smtp_server(extensions: &'static [&'static str]) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let buf = log.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let buf = buf.clone();
+                thread::spawn(move || serve_smtp(stream, extensions, "", buf));
+            }
+        });
 
-fn collect_difference(data: Vec<String>, other: Vec<String>) -> Vec<String> {
-    let mut buf = Vec::new();
-    let mut key = Vec::new();
+        return (port, log);
+    }
 
-    for item in other {
-        if !key.contains(&item) {
-            key.push(item);
-        }
+    fn start_smtps_server() -> (u16, Arc<Mutex<Vec<String>>>) {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_certificate_file("test/smime.crt", SslFiletype::PEM)
+            .unwrap();
+        acceptor
+            .set_private_key_file("test/smime.key", SslFiletype::PEM)
+            .unwrap();
+        let acceptor = Arc::new(acceptor.build());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let buf = log.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let acceptor = acceptor.clone();
+                let buf = buf.clone();
+                thread::spawn(move || {
+                    if let Ok(s) = acceptor.accept(stream) {
+                        serve_smtp(s, &["AUTH PLAIN LOGIN"], "", buf);
+                    }
+                });
+            }
+        });
+
+        return (port, log);
     }
 
-    for item in data {
-        if !key.contains(&item) {
-            buf.push(item);
+    fn start_flaky_smtp_server(
+        failures: usize,
+        reject: &'static str,
+    ) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let buf = log.clone();
+
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let stream = stream.unwrap();
+                let buf = buf.clone();
+                let reject = if index < failures { reject } else { "" };
+                thread::spawn(move || serve_smtp(stream, &[], reject, buf));
+            }
+        });
+
+        return (port, log);
+    }
+
+    fn serve_smtp<S: Read + Write>(
+        stream: S,
+        extensions: &[&str],
+        reject: &str,
+        buf: Arc<Mutex<Vec<String>>>,
+    ) {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        if reader
+            .get_mut()
+            .write_all(b"220 localhost ESMTP\r\n")
+            .is_err()
+        {
+            return;
+        }
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let cmd = line.trim_end().to_string();
+            line.clear();
+            buf.lock().unwrap().push(cmd.to_owned());
+            let reply = match cmd.split(' ').next().unwrap().to_uppercase().as_str() {
+                "EHLO" => {
+                    let mut reply = "250-localhost\r\n".to_string();
+                    for item in extensions {
+                        reply.push_str(&format!("250-{}\r\n", item));
+                    }
+                    reply + "250 8BITMIME\r\n"
+                }
+                "DATA" => {
+                    if reader.get_mut().write_all(b"354 go ahead\r\n").is_err() {
+                        break;
+                    }
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        line.clear();
+                    }
+                    line.clear();
+                    "250 OK\r\n".to_string()
+                }
+                "AUTH" => "235 authenticated\r\n".to_string(),
+                "MAIL" if !reject.is_empty() => format!("{}\r\n", reject),
+                "QUIT" => "221 bye\r\n".to_string(),
+                _ => "250 OK\r\n".to_string(),
+            };
+            if reader.get_mut().write_all(reply.as_bytes()).is_err() {
+                break;
+            }
         }
     }
 
-    return buf;
-}
+    fn build_tls_config(port: u16, mode: &str, insecure: bool) -> Config {
+        let mut config = parse_config("test/valid.json").unwrap();
+        config.host = "127.0.0.1".to_string();
+        config.port = port;
+        config.tls = Some(Tls {
+            ca: "".to_string(),
+            cert: "".to_string(),
+            insecure,
+            key: "".to_string(),
+            mode: mode.to_string(),
+        });
+
+        config
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::HashSet;
+    #[test]
+    fn test_build_tls() {
+        let mut config = build_tls_config(25, TLS_NONE, false);
+        assert!(matches!(build_tls(&config), Ok(ClientTls::None)));
+
+        for (mode, ret) in [
+            (TLS_IMPLICIT, "Wrapper"),
+            (TLS_STARTTLS_OPPORTUNISTIC, "Opportunistic"),
+            (TLS_STARTTLS_REQUIRED, "Required"),
+        ] {
+            config.tls.as_mut().unwrap().mode = mode.to_string();
+            assert!(format!("{:?}", build_tls(&config).unwrap()).starts_with(ret));
+        }
+
+        let tls = config.tls.as_mut().unwrap();
+        tls.ca = "test/smime.crt".to_string();
+        tls.cert = "test/smime.crt".to_string();
+        tls.key = "test/smime.key".to_string();
+        assert!(build_tls(&config).is_ok());
+
+        config.tls.as_mut().unwrap().key = "".to_string();
+        assert!(build_tls(&config).is_err());
+
+        config.tls.as_mut().unwrap().mode = "ssl".to_string();
+        assert!(build_tls(&config).is_err());
+
+        config.tls = None;
+        assert!(format!("{:?}", build_tls(&config).unwrap()).starts_with("Wrapper"));
+    }
 
     #[test]
-    fn test_parse_config() {
-        assert!(parse_config("test/valid.json").is_ok());
-        assert!(parse_config("test/invalid.json").is_err());
+    fn test_build_auth() {
+        let mut config = parse_config("test/valid.json").unwrap();
+        assert!(build_auth(&config).unwrap().is_none());
+
+        config.user = "sender".to_string();
+        config.pass = "pass".to_string();
+        let (_, mechanisms) = build_auth(&config).unwrap().unwrap();
+        assert_eq!(mechanisms, vec![Mechanism::Plain, Mechanism::Login]);
+
+        config.auth = Some(Auth {
+            client_id: "".to_string(),
+            client_secret: "".to_string(),
+            mechanism: "none".to_string(),
+            refresh_token: "".to_string(),
+            scope: "".to_string(),
+            token_url: "".to_string(),
+        });
+        assert!(build_auth(&config).unwrap().is_none());
+
+        for (mechanism, ret) in [
+            ("login", Mechanism::Login),
+            ("PLAIN", Mechanism::Plain),
+            ("xoauth2", Mechanism::Xoauth2),
+        ] {
+            config.auth.as_mut().unwrap().mechanism = mechanism.to_string();
+            let (_, mechanisms) = build_auth(&config).unwrap().unwrap();
+            assert_eq!(mechanisms, vec![ret]);
+        }
+
+        config.auth.as_mut().unwrap().mechanism = "CRAM-MD5".to_string();
+        assert!(build_auth(&config).is_err());
+
+        config.user = "".to_string();
+        config.pass = "".to_string();
+        config.auth.as_mut().unwrap().mechanism = "PLAIN".to_string();
+        assert!(build_auth(&config).unwrap().is_none());
+
+        config.pass = "ya29.token".to_string();
+        config.auth.as_mut().unwrap().mechanism = "XOAUTH2".to_string();
+        assert!(build_auth(&config).is_err());
     }
 
     #[test]
-    fn test_parse_attachment() {
-        let config = parse_config("test/valid.json").unwrap();
+    fn test_send_mail_auth() {
+        let mail = Mail {
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            subject: "Build 42".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
 
-        match parse_attachment(&config, "") {
-            Ok(b) => assert!(b.is_empty()),
-            Err(_) => assert!(false),
-        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let token_port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\n\r\n{\"access_token\":\"ya29.token\"}")
+                .unwrap();
+        });
+
+        let (port, log) = start_smtp_server(&["AUTH PLAIN LOGIN XOAUTH2"]);
+        let mut config = build_tls_config(port, TLS_NONE, false);
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+
+        config.user = "sender@example.com".to_string();
+        config.pass = "pass".to_string();
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+
+        config.auth = Some(Auth {
+            client_id: "rsmail".to_string(),
+            client_secret: "".to_string(),
+            mechanism: AUTH_XOAUTH2.to_string(),
+            refresh_token: "1//refresh".to_string(),
+            scope: "".to_string(),
+            token_url: format!("http://127.0.0.1:{}/token", token_port),
+        });
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+
+        let log = log.lock().unwrap().clone();
+        let auth: Vec<&String> = log.iter().filter(|l| l.starts_with("AUTH")).collect();
+        assert_eq!(auth.len(), 2);
+        assert!(auth[0].starts_with("AUTH PLAIN "));
+        assert_eq!(
+            auth[1].as_str(),
+            format!(
+                "AUTH XOAUTH2 {}",
+                openssl::base64::encode_block(
+                    b"user=sender@example.com\x01auth=Bearer ya29.token\x01\x01"
+                )
+            )
+        );
+    }
 
-        let name = "attach1.txt,attach2.txt";
-        assert!(parse_attachment(&config, name).is_err());
+    #[test]
+    fn test_send_mail_retry() {
+        let mail = Mail {
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            subject: "Build 42".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
 
-        let name = "test/attach1.txt,test/attach2.txt";
-        match parse_attachment(&config, name) {
-            Ok(b) => assert_eq!(b.len(), 2),
-            Err(_) => assert!(false),
-        }
+        let count = |log: &Arc<Mutex<Vec<String>>>| {
+            let log = log.lock().unwrap().clone();
+            log.iter().filter(|l| l.starts_with("MAIL FROM")).count()
+        };
+
+        let (port, log) = start_flaky_smtp_server(2, "451 4.3.0 Try again later");
+        let mut config = build_tls_config(port, TLS_NONE, false);
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_err());
+        assert_eq!(count(&log), 1);
+
+        config.retry = Some(Retry {
+            attempts: 3,
+            delay: 1,
+            jitter: 0.5,
+            max_delay: 0,
+            multiplier: 0.0,
+        });
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+        assert_eq!(count(&log), 3);
+
+        let (port, log) = start_flaky_smtp_server(5, "550 5.1.1 User unknown");
+        config.port = port;
+        let transport = build_transport(&config, "", false).unwrap();
+        let err = send_mail(&config, &transport, &mail).unwrap_err();
+        assert_eq!(
+            retry::describe(err.as_ref()),
+            "permanent 550: 5.1.1 User unknown"
+        );
+        assert_eq!(count(&log), 1);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        config.port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let transport = build_transport(&config, "", false).unwrap();
+        let err = send_mail(&config, &transport, &mail).unwrap_err();
+        assert!(retry::is_transient(err.as_ref()));
     }
 
     #[test]
-    fn test_parse_body() {
-        assert!(parse_body("").is_ok());
+    fn test_send_mail_queue() {
+        let mail = Mail {
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            subject: "Build 42".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
 
-        match parse_body("body") {
-            Ok(b) => assert_eq!(b, "body"),
-            Err(_) => assert!(false),
-        }
+        let dir = env::temp_dir().join(format!(
+            "sender-queue-{}",
+            &mime::build_boundary().unwrap()[..16]
+        ));
+        let (port, log) = start_flaky_smtp_server(1, "451 4.3.0 Try again later");
+        let mut config = build_tls_config(port, TLS_NONE, false);
+        config.queue = Some(Queue {
+            attempts: 0,
+            delay: 0,
+            dir: dir.to_str().unwrap().to_string(),
+            expiry: 0,
+            interval: 0,
+        });
+
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+
+        let queue = config.queue.as_ref().unwrap();
+        let ret = queue::list(queue).unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].dir, queue::QUEUE_DIR);
+
+        let ret = queue::run(queue, true, |e, d| send_raw(&transport, e, d)).unwrap();
+        assert_eq!(ret.sent, 1);
+        assert!(queue::list(queue).unwrap().is_empty());
+
+        let log = log.lock().unwrap().clone();
+        assert_eq!(log.iter().filter(|l| l.starts_with("MAIL FROM")).count(), 2);
+        assert!(log.iter().any(|l| l.contains("RCPT TO:<alen@example.com>")));
+
+        let transport = Mailer::Queue(queue.clone());
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+        assert_eq!(queue::list(queue).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        match parse_body("body.txt") {
-            Ok(b) => assert_eq!(b, "body.txt"),
-            Err(_) => assert!(false),
-        }
+    #[cfg(unix)]
+    #[test]
+    fn test_send_mail_transport() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mail = Mail {
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            subject: "Build 42".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
 
-        assert!(parse_body("test/body.txt").is_ok());
+        let dir = env::temp_dir().join(format!(
+            "sender-transport-{}",
+            &mime::build_boundary().unwrap()[..16]
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        // A stand-in for sendmail that records its arguments and input
+        let command = dir.join("sendmail");
+        fs::write(
+            &command,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {0}/args\ncat > {0}/data\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&command, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut config = parse_config("test/valid.json").unwrap();
+        config.transport = Some(Delivery {
+            address: "".to_string(),
+            command: command.to_str().unwrap().to_string(),
+            dir: "".to_string(),
+            kind: TRANSPORT_SENDMAIL.to_string(),
+        });
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(matches!(transport, Mailer::Sendmail(_)));
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+        assert_eq!(
+            fs::read_to_string(dir.join("args")).unwrap(),
+            format!("-i -f {} -- alen@example.com\n", config.sender)
+        );
+        assert!(fs::read_to_string(dir.join("data"))
+            .unwrap()
+            .contains("Subject: Build 42\r\n"));
+
+        // EX_TEMPFAIL from sendmail parks the message in the queue
+        let tempfail = dir.join("sendmail-tempfail");
+        fs::write(&tempfail, "#!/bin/sh\ncat > /dev/null\nexit 75\n").unwrap();
+        fs::set_permissions(&tempfail, fs::Permissions::from_mode(0o755)).unwrap();
+        config.queue = Some(Queue {
+            attempts: 0,
+            delay: 0,
+            dir: dir.join("spool").to_str().unwrap().to_string(),
+            expiry: 0,
+            interval: 0,
+        });
+        let transport = Mailer::Sendmail(tempfail.to_str().unwrap().to_string());
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+        assert_eq!(
+            queue::list(config.queue.as_ref().unwrap()).unwrap().len(),
+            1
+        );
+        config.queue = None;
+
+        let maildir = dir.join("Maildir");
+        config.transport = Some(Delivery {
+            address: "".to_string(),
+            command: "".to_string(),
+            dir: maildir.to_str().unwrap().to_string(),
+            kind: TRANSPORT_MAILDIR.to_string(),
+        });
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+        let item = fs::read_dir(maildir.join("new")).unwrap().next().unwrap();
+        let data = fs::read_to_string(item.unwrap().path()).unwrap();
+        assert!(data.starts_with(&format!("Return-Path: <{}>\n", config.sender)));
+        assert!(data.contains("Subject: Build 42\n"));
+
+        config.transport.as_mut().unwrap().dir = "".to_string();
+        assert!(build_transport(&config, "", false).is_err());
+        config.transport.as_mut().unwrap().kind = "uucp".to_string();
+        assert!(build_transport(&config, "", false).is_err());
+        config.transport.as_mut().unwrap().kind = TRANSPORT_SMTP.to_string();
+        assert!(matches!(
+            build_transport(&config, "", false),
+            Ok(Mailer::Smtp(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_parse_content_type() {
-        assert!(parse_content_type("FOO").is_err());
+    fn test_send_mail_lmtp() {
+        let mail = Mail {
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            subject: "Build 42".to_string(),
+            to: vec![
+                "alen@example.com".to_string(),
+                "catherine@example.com".to_string(),
+            ],
+            ..Default::default()
+        };
 
-        if let Ok(b) = parse_content_type("HTML") {
-            assert_eq!(b, "text/html".to_string());
-        }
+        // Defers catherine on every other session and always rejects bob
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let buf = log.clone();
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut rcpt: Vec<String> = Vec::new();
+                stream.write_all(b"220 localhost LMTP\r\n").unwrap();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let reply = if line.starts_with("RCPT") {
+                        buf.lock().unwrap().push(line.trim_end().to_string());
+                        rcpt.push(line.clone());
+                        "250 OK\r\n".to_string()
+                    } else if line.starts_with("DATA") {
+                        "354 Go ahead\r\n".to_string()
+                    } else if line == ".\r\n" {
+                        rcpt.iter()
+                            .map(|r| {
+                                if r.contains("bob") {
+                                    "550 5.1.1 User unknown\r\n"
+                                } else if index % 2 == 0 && r.contains("catherine") {
+                                    "452 4.2.2 Over quota\r\n"
+                                } else {
+                                    "250 2.1.5 Delivered\r\n"
+                                }
+                            })
+                            .collect()
+                    } else if line.starts_with("QUIT") {
+                        stream.write_all(b"221 Bye\r\n").unwrap();
+                        break;
+                    } else if line.starts_with("LHLO") || line.starts_with("MAIL") {
+                        "250 OK\r\n".to_string()
+                    } else {
+                        continue;
+                    };
+                    stream.write_all(reply.as_bytes()).unwrap();
+                }
+            }
+        });
+
+        let mut config = parse_config("test/valid.json").unwrap();
+        config.transport = Some(Delivery {
+            address: format!("127.0.0.1:{}", port),
+            command: "".to_string(),
+            dir: "".to_string(),
+            kind: TRANSPORT_LMTP.to_string(),
+        });
+        config.retry = Some(Retry {
+            attempts: 2,
+            delay: 1,
+            jitter: 0.0,
+            max_delay: 0,
+            multiplier: 0.0,
+        });
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+        assert_eq!(
+            log.lock().unwrap().clone(),
+            vec![
+                "RCPT TO:<alen@example.com>",
+                "RCPT TO:<catherine@example.com>",
+                "RCPT TO:<catherine@example.com>",
+            ]
+        );
+
+        config.retry = None;
+        let err = send_mail(&config, &transport, &mail).unwrap_err();
+        assert!(retry::is_transient(err.as_ref()));
+        assert_eq!(
+            err.to_string(),
+            "lmtp delivery failed for 1 of 2 recipients: \
+             catherine@example.com: 452 4.2.2 Over quota"
+        );
+
+        // A mixed reply drops bob and queues catherine alone
+        let mut mail = mail;
+        mail.to.insert(1, "bob@example.com".to_string());
+        let dir = env::temp_dir().join(format!(
+            "sender-lmtp-{}",
+            &mime::build_boundary().unwrap()[..16]
+        ));
+        config.queue = Some(Queue {
+            attempts: 0,
+            delay: 0,
+            dir: dir.to_str().unwrap().to_string(),
+            expiry: 0,
+            interval: 0,
+        });
+        let queue = config.queue.as_ref().unwrap();
+
+        // With only bob failing there is nothing to queue
+        let err = send_mail(&config, &transport, &mail).unwrap_err();
+        assert!(!retry::is_transient(err.as_ref()));
+        assert!(queue::list(queue).unwrap().is_empty());
+
+        let err = send_mail(&config, &transport, &mail).unwrap_err();
+        assert_eq!(err.to_string(), "lmtp rejected recipients: bob@example.com");
+
+        let ret = queue::list(queue).unwrap();
+        assert_eq!(ret.len(), 1);
+        let to: Vec<String> = ret[0]
+            .entry
+            .envelope
+            .to()
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(to, vec!["catherine@example.com"]);
+
+        let ret = queue::run(queue, true, |e, d| send_raw(&transport, e, d)).unwrap();
+        assert_eq!(ret.sent, 1);
+        assert!(queue::list(queue).unwrap().is_empty());
+        assert_eq!(
+            log.lock().unwrap()[3..],
+            [
+                "RCPT TO:<alen@example.com>",
+                "RCPT TO:<catherine@example.com>",
+                "RCPT TO:<alen@example.com>",
+                "RCPT TO:<bob@example.com>",
+                "RCPT TO:<catherine@example.com>",
+                "RCPT TO:<alen@example.com>",
+                "RCPT TO:<bob@example.com>",
+                "RCPT TO:<catherine@example.com>",
+                "RCPT TO:<catherine@example.com>",
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        config.transport.as_mut().unwrap().address = "".to_string();
+        assert!(build_transport(&config, "", false).is_err());
+    }
 
-        if let Ok(b) = parse_content_type("PLAIN_TEXT") {
-            assert_eq!(b, "text/plain".to_string());
+    #[test]
+    fn test_send_mail_tls() {
+        let mail = Mail {
+            body: "body".to_string(),
+            content_type: "text/plain".to_string(),
+            subject: "Build 42".to_string(),
+            to: vec!["alen@example.com".to_string()],
+            ..Default::default()
+        };
+
+        let (port, log) = start_smtp_server(&["AUTH PLAIN LOGIN"]);
+        for (mode, ok) in [
+            (TLS_NONE, true),
+            (TLS_STARTTLS_OPPORTUNISTIC, true),
+            (TLS_STARTTLS_REQUIRED, false),
+        ] {
+            let config = build_tls_config(port, mode, false);
+            let transport = build_transport(&config, "", false).unwrap();
+            assert_eq!(send_mail(&config, &transport, &mail).is_ok(), ok);
         }
+        let log = log.lock().unwrap().clone();
+        assert_eq!(log.iter().filter(|l| l.starts_with("DATA")).count(), 2);
+        assert!(!log.iter().any(|l| l.starts_with("STARTTLS")));
+
+        let (port, log) = start_smtps_server();
+        let config = build_tls_config(port, TLS_IMPLICIT, false);
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_err());
+
+        let config = build_tls_config(port, TLS_IMPLICIT, true);
+        let transport = build_transport(&config, "", false).unwrap();
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+        let log = log.lock().unwrap().clone();
+        assert!(log.contains(&"RCPT TO:<alen@example.com>".to_string()));
     }
 
     #[test]
-    fn test_parse_recipients() {
+    fn test_send_mail_smtputf8() {
         let config = parse_config("test/valid.json").unwrap();
 
-        let recipients = "alen@example.com";
-        let (cc, to) = parse_recipients(&config, recipients);
-        assert!(cc.is_empty());
-        assert_eq!(to.len(), 1);
-        assert_eq!(to[0], "alen@example.com");
+        let mut mail = Mail {
+            body: "body".to_string(),
+            cc: vec!["bob@example.com".to_string()],
+            content_type: "text/plain".to_string(),
+            from: "Jörg Müller <jorg@bücher.de>".to_string(),
+            subject: "Build 42 报告".to_string(),
+            to: vec!["张伟 <zhang@例子.测试>".to_string()],
+            ..Default::default()
+        };
 
-        let recipients = "alen@example.com,cc:,cc:bob@example.com,";
-        let (cc, to) = parse_recipients(&config, recipients);
-        assert_eq!(cc.len(), 1);
-        assert_eq!(cc[0], "bob@example.com");
-        assert_eq!(to.len(), 1);
-        assert_eq!(to[0], "alen@example.com");
+        let (port, log) = start_smtp_server(&["SMTPUTF8"]);
+        let transport = Mailer::Smtp(
+            SmtpTransport::builder_dangerous("127.0.0.1")
+                .port(port)
+                .build(),
+        );
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+
+        let log = log.lock().unwrap().clone();
+        assert!(log
+            .iter()
+            .any(|l| l.starts_with("MAIL FROM:<jorg@bücher.de>") && l.contains("SMTPUTF8")));
+        assert!(log.contains(&"RCPT TO:<zhang@例子.测试>".to_string()));
+
+        let (port, log) = start_smtp_server(&[]);
+        let transport = Mailer::Smtp(
+            SmtpTransport::builder_dangerous("127.0.0.1")
+                .port(port)
+                .build(),
+        );
+        assert!(send_mail(&config, &transport, &mail).is_ok());
+
+        let log = log.lock().unwrap().clone();
+        assert!(
+            log.iter()
+                .any(|l| l.starts_with("MAIL FROM:<jorg@xn--bcher-kva.de>")
+                    && !l.contains("SMTPUTF8"))
+        );
+        assert!(log.contains(&"RCPT TO:<zhang@xn--fsqu00a.xn--0zwm56d>".to_string()));
+        assert!(log.contains(&"RCPT TO:<bob@example.com>".to_string()));
+
+        mail.to = vec!["张伟@example.com".to_string()];
+        assert!(send_mail(&config, &transport, &mail).is_err());
+    }
 
-        let recipients = "alen@example.com,alen@example.com,cc:bob@example.com,cc:bob@example.com,";
-        let (cc, to) = parse_recipients(&config, recipients);
-        assert_eq!(cc.len(), 1);
-        assert_eq!(cc[0], "bob@example.com");
-        assert_eq!(to.len(), 1);
-        assert_eq!(to[0], "alen@example.com");
+    #[test]
+    fn test_embed_inline() {
+        let config = parse_config("test/valid.json").unwrap();
 
-        let recipients = "alen@example.com,bob@example.com,cc:bob@example.com,cc:bob@example.com,";
-        let (cc, to) = parse_recipients(&config, recipients);
-        assert!(cc.is_empty());
-        assert_eq!(to.len(), 2);
-        assert_eq!(to[0], "alen@example.com");
-        assert_eq!(to[1], "bob@example.com");
+        let mut inline = parse_attachment(&config, "test/attach3.png").unwrap();
+        let body = "<p><IMG alt='logo' SRC='./test/attach3.png'></p>";
+        let buf = embed_inline(body, "", &mut inline).unwrap();
+        assert_eq!(
+            buf,
+            "<p><IMG alt='logo' SRC=\"cid:inline1.attach3.png\"></p>"
+        );
+        assert_eq!(inline.len(), 1);
+
+        let mut inline = vec![];
+        let body = "<img src=\"file:test/attach3.png\"><img src=\"test/attach1.txt\">";
+        let buf = embed_inline(body, "", &mut inline).unwrap();
+        assert_eq!(
+            buf,
+            "<img src=\"cid:inline1.attach3.png\"><img src=\"test/attach1.txt\">"
+        );
+        assert_eq!(inline.len(), 1);
+        assert_eq!(inline[0].content_type, "image/png");
+
+        let mut inline = vec![];
+        let body = "<img src=\"https://example.com/logo.png\"><img src=\"cid:logo\"><img src=\"logo.png\">";
+        let buf = embed_inline(body, "", &mut inline).unwrap();
+        assert_eq!(buf, body);
+        assert!(inline.is_empty());
+
+        let name = "test/report/index.html";
+        assert_eq!(body_dir(name), "test/report");
+        assert_eq!(body_dir("<p>text</p>"), "");
+
+        let mut inline = vec![];
+        let body = parse_body(name).unwrap();
+        let buf = embed_inline(&body, &body_dir(name), &mut inline).unwrap();
+        assert_eq!(
+            buf,
+            "<p><img src=\"cid:inline1.chart.png\"><img src=\"notes.txt\"></p>\n"
+        );
+        assert_eq!(inline.len(), 1);
+        assert!(inline[0].path.ends_with("test/report/chart.png"));
     }
 
     #[test]
-    fn test_send_mail() {
-        assert!(true);
+    fn test_render_text() {
+        let html = "<h1>Title</h1><p>See <a href=\"https://example.com\">report</a>.</p>";
+        let buf = render_text(html);
+        assert!(buf.contains("Title"));
+        assert!(buf.contains("[report][1]"));
+        assert!(buf.contains("[1]: https://example.com"));
     }
 
     #[test]
@@ -459,6 +3882,112 @@ mod tests {
         assert!(check_file("test/body.txt").is_ok());
     }
 
+    #[test]
+    fn test_detect_content_type() {
+        assert_eq!(
+            detect_content_type("test/attach1.txt").unwrap(),
+            "text/plain"
+        );
+        assert_eq!(
+            detect_content_type("test/attach3.png").unwrap(),
+            "image/png"
+        );
+        assert_eq!(
+            detect_content_type("test/attach4").unwrap(),
+            DEFAULT_CONTENT_TYPE
+        );
+        assert!(detect_content_type("test/attach5")
+            .unwrap_err()
+            .to_string()
+            .starts_with("test/attach5: "));
+    }
+
+    #[test]
+    fn test_rename_duplicates() {
+        let helper = |name: &str| -> Attach {
+            Attach {
+                content_type: DEFAULT_CONTENT_TYPE.to_string(),
+                name: name.to_string(),
+                path: name.to_string(),
+            }
+        };
+
+        let mut buf = vec![helper("attach"), helper("attach"), helper("attach.txt")];
+        rename_duplicates(&mut buf);
+        assert_eq!(buf[0].name, "attach");
+        assert_eq!(buf[1].name, "attach (1)");
+        assert_eq!(buf[2].name, "attach.txt");
+    }
+
+    #[test]
+    fn test_split_recipients() {
+        let buf = split_recipients("", ",");
+        assert_eq!(buf, vec!["".to_string()]);
+
+        let buf = split_recipients("alen@example.com, bob@example.com,", ",");
+        assert_eq!(buf, vec!["alen@example.com", "bob@example.com", ""]);
+
+        let buf = split_recipients("\"Smith, \\\"Alen\\\"\" <alen@example.com>;bob", ";");
+        assert_eq!(
+            buf,
+            vec!["\"Smith, \\\"Alen\\\"\" <alen@example.com>", "bob"]
+        );
+
+        let buf = split_recipients("\"Smith, Alen\" <alen@example.com>,,bob", ",,");
+        assert_eq!(buf, vec!["\"Smith, Alen\" <alen@example.com>", "bob"]);
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("alen@example.com"), "alen@example.com");
+        assert_eq!(parse_address(" alen@example.com "), "alen@example.com");
+        assert_eq!(parse_address("Alen <alen@example.com>"), "alen@example.com");
+        assert_eq!(
+            parse_address("\"<Alen>\" <alen@example.com>"),
+            "alen@example.com"
+        );
+    }
+
+    #[test]
+    fn test_normalize_address() {
+        let buf = normalize_address("Jörg <jorg@BÜCHER.de>");
+        assert_eq!(buf, "jorg@xn--bcher-kva.de");
+
+        let buf = normalize_address("Alen@Example.COM");
+        assert_eq!(buf, "Alen@example.com");
+
+        let buf = normalize_address("alen");
+        assert_eq!(buf, "alen");
+    }
+
+    #[test]
+    fn test_encode_domain() {
+        let buf = encode_domain("张伟 <zhang@例子.测试>").unwrap();
+        assert_eq!(buf, "张伟 <zhang@xn--fsqu00a.xn--0zwm56d>");
+
+        let buf = encode_domain("jorg@bücher.de").unwrap();
+        assert_eq!(buf, "jorg@xn--bcher-kva.de");
+
+        assert!(encode_domain("张伟@example.com").is_err());
+    }
+
+    #[test]
+    fn test_format_mailbox() {
+        assert_eq!(format_mailbox("", "alen@example.com"), "alen@example.com");
+        assert_eq!(
+            format_mailbox("Alen Smith", "alen@example.com"),
+            "Alen Smith <alen@example.com>"
+        );
+        assert_eq!(
+            format_mailbox("Smith, \"Alen\"", "alen@example.com"),
+            "\"Smith, \\\"Alen\\\"\" <alen@example.com>"
+        );
+
+        let buf = format_mailbox("Smith, Alen", "alen@example.com");
+        let mailbox: Mailbox = buf.parse().unwrap();
+        assert_eq!(mailbox.name, Some("Smith, Alen".to_string()));
+    }
+
     #[test]
     fn test_remove_duplicates() {
         let helper = |data: Vec<String>| -> bool {
@@ -479,6 +4008,13 @@ mod tests {
 
         buf = remove_duplicates(buf);
         assert!(!helper(buf));
+
+        let buf = vec![
+            "Alen <alen@example.com>".to_string(),
+            "alen@example.com".to_string(),
+        ];
+        let buf = remove_duplicates(buf);
+        assert_eq!(buf, vec!["Alen <alen@example.com>".to_string()]);
     }
 
     #[test]
@@ -502,5 +4038,10 @@ mod tests {
         let buf = collect_difference(buf_a, buf_b);
         assert_eq!(buf.len(), 1);
         assert_eq!(buf[0], "bob@example.com");
+
+        let buf_a = vec!["Alen <alen@example.com>".to_string()];
+        let buf_b = vec!["Smith <alen@example.com>".to_string()];
+        let buf = collect_difference(buf_a, buf_b);
+        assert!(buf.is_empty());
     }
 }
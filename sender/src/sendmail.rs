@@ -0,0 +1,140 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use lettre::address::Envelope;
+
+// sysexits(3) code for a failure the caller should try again later
+const EX_TEMPFAIL: i32 = 75;
+
+#[derive(Debug)]
+pub struct ExitError {
+    pub code: Option<i32>,
+    pub text: String,
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(c) => write!(f, "sendmail exited with {}: {}", c, self.text),
+            None => write!(f, "sendmail killed by signal: {}", self.text),
+        }
+    }
+}
+
+impl Error for ExitError {}
+
+pub fn is_transient(err: &ExitError) -> bool {
+    return err.code == Some(EX_TEMPFAIL);
+}
+
+// Same arguments as lettre's sendmail transport, which does not report the exit code
+pub fn send(command: &str, envelope: &Envelope, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut cmd = Command::new(command);
+    cmd.arg("-i");
+    if let Some(from) = envelope.from() {
+        cmd.arg("-f").arg(from);
+    }
+
+    let mut process = cmd
+        .arg("--")
+        .args(envelope.to())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {}", command, e))?;
+
+    process
+        .stdin
+        .take()
+        .ok_or("failed to open sendmail stdin")?
+        .write_all(data)?;
+    let output = process.wait_with_output()?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    return Err(Box::new(ExitError {
+        code: output.status.code(),
+        text: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    }));
+}
+
+// The stand-in commands are shell scripts
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use crate::mime::build_boundary;
+
+    fn build_command(dir: &Path, code: i32) -> String {
+        let command = dir.join(format!("sendmail-{}", code));
+        fs::write(
+            &command,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {0}/args\ncat > {0}/data\necho deferred >&2\nexit {1}\n",
+                dir.display(),
+                code
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&command, fs::Permissions::from_mode(0o755)).unwrap();
+
+        command.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_send() {
+        let dir = env::temp_dir().join(format!(
+            "sender-sendmail-{}",
+            &build_boundary().unwrap()[..16]
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let envelope = Envelope::new(
+            Some("sender@example.com".parse().unwrap()),
+            vec![
+                "alen@example.com".parse().unwrap(),
+                "bob@example.com".parse().unwrap(),
+            ],
+        )
+        .unwrap();
+
+        assert!(send(&build_command(&dir, 0), &envelope, b"body").is_ok());
+        assert_eq!(
+            fs::read_to_string(dir.join("args")).unwrap(),
+            "-i -f sender@example.com -- alen@example.com bob@example.com\n"
+        );
+        assert_eq!(fs::read(dir.join("data")).unwrap(), b"body");
+
+        let err = send(&build_command(&dir, 75), &envelope, b"body").unwrap_err();
+        let err = err.downcast_ref::<ExitError>().unwrap();
+        assert!(is_transient(err));
+        assert_eq!(err.to_string(), "sendmail exited with 75: deferred");
+
+        let err = send(&build_command(&dir, 1), &envelope, b"body").unwrap_err();
+        assert!(!is_transient(err.downcast_ref().unwrap()));
+
+        assert!(send(dir.join("missing").to_str().unwrap(), &envelope, b"body").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
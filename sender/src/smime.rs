@@ -0,0 +1,238 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::symm::Cipher;
+use openssl::x509::X509;
+
+use crate::mime::{build_boundary, encode_base64, normalize_newlines, split_entity};
+use crate::Smime;
+
+pub fn protect(
+    data: &[u8],
+    recipients: &[String],
+    config: &Smime,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let data = normalize_newlines(data);
+    let (mut headers, mut entity) = split_entity(&data)?;
+
+    if !config.cert.is_empty() && !config.key.is_empty() {
+        entity = sign(&entity, config)?;
+    }
+
+    if !config.certs.is_empty() {
+        entity = encrypt(&entity, recipients, config)?;
+    }
+
+    headers.extend_from_slice(&entity);
+
+    return Ok(headers);
+}
+
+fn sign(entity: &[u8], config: &Smime) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cert = X509::from_pem(&fs::read(&config.cert)?)?;
+    let key = PKey::private_key_from_pem(&fs::read(&config.key)?)?;
+
+    // The entity is already canonical CRLF text so it is signed as is (RFC 8551 section 3.1.1)
+    let flags = Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY;
+    let chain: Stack<X509> = Stack::new()?;
+    let pkcs7 = Pkcs7::sign(&cert, &key, &chain, entity, flags)?;
+
+    let boundary = build_boundary()?;
+    let mut buf = format!(
+        "Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\";\r\n \
+         micalg=sha-256; boundary=\"{}\"\r\n\r\n--{}\r\n",
+        boundary, boundary
+    )
+    .into_bytes();
+
+    buf.extend_from_slice(entity);
+    buf.extend_from_slice(
+        format!(
+            "\r\n--{}\r\n\
+             Content-Type: application/pkcs7-signature; name=\"smime.p7s\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             Content-Disposition: attachment; filename=\"smime.p7s\"\r\n\r\n\
+             {}\r\n--{}--\r\n",
+            boundary,
+            encode_base64(&pkcs7.to_der()?),
+            boundary
+        )
+        .as_bytes(),
+    );
+
+    return Ok(buf);
+}
+
+fn encrypt(
+    entity: &[u8],
+    recipients: &[String],
+    config: &Smime,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut certs = Stack::new()?;
+
+    for item in recipients {
+        let path = Path::new(&config.certs).join(format!("{}.pem", item.to_lowercase()));
+        if !path.is_file() {
+            return Err(format!("smime certificate not found for {}", item).into());
+        }
+        certs.push(X509::from_pem(&fs::read(path)?)?)?;
+    }
+
+    // Encrypt to the signer as well so the sent copy stays readable
+    if !config.cert.is_empty() {
+        certs.push(X509::from_pem(&fs::read(&config.cert)?)?)?;
+    }
+
+    let pkcs7 = Pkcs7::encrypt(&certs, entity, Cipher::aes_256_cbc(), Pkcs7Flags::BINARY)?;
+
+    let buf = format!(
+        "Content-Type: application/pkcs7-mime; smime-type=enveloped-data;\r\n \
+         name=\"smime.p7m\"\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         Content-Disposition: attachment; filename=\"smime.p7m\"\r\n\r\n\
+         {}\r\n",
+        encode_base64(&pkcs7.to_der()?)
+    );
+
+    return Ok(buf.into_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::{split_headers, split_message};
+    use openssl::base64;
+    use openssl::x509::store::X509StoreBuilder;
+
+    const DATA: &[u8] = b"From: sender@example.com\r\n\
+                          To: bob@example.com\r\n\
+                          Subject: Release notice\r\n\
+                          MIME-Version: 1.0\r\n\
+                          Content-Type: text/plain; charset=utf-8\r\n\
+                          Content-Transfer-Encoding: 7bit\r\n\
+                          \r\n\
+                          Release 1.0.0 is out.\r\n";
+
+    const ENTITY: &[u8] = b"Content-Type: text/plain; charset=utf-8\r\n\
+                            Content-Transfer-Encoding: 7bit\r\n\
+                            \r\n\
+                            Release 1.0.0 is out.\r\n";
+
+    fn build_config(cert: &str, key: &str, certs: &str) -> Smime {
+        Smime {
+            cert: cert.to_string(),
+            certs: certs.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    fn decode_part(data: &[u8]) -> Vec<u8> {
+        let (_, body) = split_message(data);
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let body: String = body.lines().take_while(|l| !l.starts_with("--")).collect();
+
+        base64::decode_block(&body).unwrap()
+    }
+
+    #[test]
+    fn test_sign() {
+        let config = build_config("test/smime.crt", "test/smime.key", "");
+        let ret = protect(DATA, &["bob@example.com".to_string()], &config).unwrap();
+
+        let (head, body) = split_message(&ret);
+        let headers = split_headers(std::str::from_utf8(head).unwrap());
+        let content_type = headers.iter().find(|(n, _)| n == "Content-Type").unwrap();
+        assert!(content_type.1.contains("multipart/signed"));
+        assert!(headers.iter().any(|(n, _)| n == "Subject"));
+
+        let boundary = content_type.1.split("boundary=\"").nth(1).unwrap();
+        let delimiter = format!("--{}", boundary.trim_end_matches('"'));
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        let parts: Vec<&str> = text.split(delimiter.as_str()).collect();
+        assert_eq!(parts.len(), 4);
+
+        let entity = parts[1]
+            .trim_start_matches("\r\n")
+            .strip_suffix("\r\n")
+            .unwrap();
+        assert_eq!(entity.as_bytes(), ENTITY);
+
+        let signature = decode_part(parts[2].trim_start_matches("\r\n").as_bytes());
+        let pkcs7 = Pkcs7::from_der(&signature).unwrap();
+
+        let cert = X509::from_pem(&fs::read("test/smime.crt").unwrap()).unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(cert).unwrap();
+        let store = store.build();
+
+        let ret = pkcs7.verify(
+            &Stack::<X509>::new().unwrap(),
+            &store,
+            Some(ENTITY),
+            None,
+            Pkcs7Flags::BINARY,
+        );
+        assert!(ret.is_ok());
+
+        let ret = pkcs7.verify(
+            &Stack::<X509>::new().unwrap(),
+            &store,
+            Some(DATA),
+            None,
+            Pkcs7Flags::BINARY,
+        );
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let config = build_config("", "", "test/certs");
+        let ret = protect(DATA, &["Bob@example.com".to_string()], &config).unwrap();
+
+        let (head, _) = split_message(&ret);
+        let headers = split_headers(std::str::from_utf8(head).unwrap());
+        let content_type = headers.iter().find(|(n, _)| n == "Content-Type").unwrap();
+        assert!(content_type.1.contains("application/pkcs7-mime"));
+
+        let pkcs7 = Pkcs7::from_der(&decode_part(&ret)).unwrap();
+        let cert = X509::from_pem(&fs::read("test/certs/bob@example.com.pem").unwrap()).unwrap();
+        let key = PKey::private_key_from_pem(&fs::read("test/bob.key").unwrap()).unwrap();
+
+        let ret = pkcs7.decrypt(&key, &cert, Pkcs7Flags::BINARY).unwrap();
+        assert_eq!(ret, ENTITY);
+
+        let ret = protect(DATA, &["carla@example.com".to_string()], &config);
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_sign_encrypt() {
+        let config = build_config("test/smime.crt", "test/smime.key", "test/certs");
+        let ret = protect(DATA, &["bob@example.com".to_string()], &config).unwrap();
+
+        let pkcs7 = Pkcs7::from_der(&decode_part(&ret)).unwrap();
+        let cert = X509::from_pem(&fs::read("test/smime.crt").unwrap()).unwrap();
+        let key = PKey::private_key_from_pem(&fs::read("test/smime.key").unwrap()).unwrap();
+
+        let ret = pkcs7.decrypt(&key, &cert, Pkcs7Flags::BINARY).unwrap();
+        let ret = String::from_utf8(ret).unwrap();
+        assert!(ret.starts_with("Content-Type: multipart/signed;"));
+        assert!(ret.contains("Release 1.0.0 is out."));
+    }
+}
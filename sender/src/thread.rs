@@ -0,0 +1,172 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use openssl::sha::sha256;
+
+use crate::mime::build_boundary;
+
+const ID_LENGTH: usize = 32;
+
+pub type State = BTreeMap<String, String>;
+
+pub struct Thread {
+    pub in_reply_to: String,
+    pub message_id: String,
+    pub references: Vec<String>,
+}
+
+// The first message of a thread is derived from the key alone, and every follow-up
+// from the key and its parent, so the same key always yields the same chain
+pub fn build_thread(key: &str, previous: &str, domain: &str) -> Thread {
+    let root = build_id(key, domain);
+
+    if previous.is_empty() {
+        return Thread {
+            in_reply_to: "".to_string(),
+            message_id: root,
+            references: vec![],
+        };
+    }
+
+    let mut references = vec![root];
+    if references[0] != previous {
+        references.push(previous.to_string());
+    }
+
+    return Thread {
+        in_reply_to: previous.to_string(),
+        message_id: build_id(&format!("{}\n{}", key, previous), domain),
+        references,
+    };
+}
+
+pub fn build_message_id(domain: &str) -> Result<String, Box<dyn Error>> {
+    return Ok(format!("<{}@{}>", build_boundary()?, domain));
+}
+
+pub fn parse_message_id(data: &str) -> Result<String, Box<dyn Error>> {
+    let id = data.trim().trim_start_matches('<').trim_end_matches('>');
+
+    match id.split_once('@') {
+        Some((l, r))
+            if !l.is_empty()
+                && !r.is_empty()
+                && !id.contains(|c: char| c.is_whitespace() || "<>,".contains(c)) =>
+        {
+            Ok(format!("<{}>", id))
+        }
+        _ => Err(format!("invalid message id {}", data).into()),
+    }
+}
+
+pub fn load_state(name: &str) -> Result<State, Box<dyn Error>> {
+    if !Path::new(name).exists() {
+        return Ok(State::new());
+    }
+
+    let data = fs::read_to_string(name)?;
+    if data.trim().is_empty() {
+        return Ok(State::new());
+    }
+
+    return serde_json::from_str(&data).map_err(|e| format!("{}: {}", name, e).into());
+}
+
+pub fn save_state(name: &str, state: &State) -> Result<(), Box<dyn Error>> {
+    // Write aside and rename so an interrupted run never leaves a truncated file
+    let tmp = format!("{}.tmp", name);
+    fs::write(&tmp, serde_json::to_string_pretty(state)? + "\n")?;
+
+    return fs::rename(&tmp, name).map_err(|e| e.into());
+}
+
+fn build_id(data: &str, domain: &str) -> String {
+    let hash: String = sha256(data.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    return format!("<{}@{}>", &hash[..ID_LENGTH], domain);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_build_thread() {
+        let root = build_thread("change-42", "", "example.com");
+        assert!(root.message_id.starts_with('<'));
+        assert!(root.message_id.ends_with("@example.com>"));
+        assert_eq!(root.message_id.len(), ID_LENGTH + 14);
+        assert!(root.in_reply_to.is_empty());
+        assert!(root.references.is_empty());
+
+        let ret = build_thread("change-42", "", "example.com");
+        assert_eq!(ret.message_id, root.message_id);
+        let ret = build_thread("change-43", "", "example.com");
+        assert_ne!(ret.message_id, root.message_id);
+
+        let reply = build_thread("change-42", &root.message_id, "example.com");
+        assert_ne!(reply.message_id, root.message_id);
+        assert_eq!(reply.in_reply_to, root.message_id);
+        assert_eq!(reply.references, vec![root.message_id.clone()]);
+
+        let ret = build_thread("change-42", &reply.message_id, "example.com");
+        assert_eq!(ret.in_reply_to, reply.message_id);
+        assert_eq!(ret.references, vec![root.message_id, reply.message_id]);
+    }
+
+    #[test]
+    fn test_parse_message_id() {
+        assert_eq!(
+            parse_message_id("<build-41@example.com>").unwrap(),
+            "<build-41@example.com>"
+        );
+        assert_eq!(
+            parse_message_id(" build-41@example.com ").unwrap(),
+            "<build-41@example.com>"
+        );
+        assert!(parse_message_id("build-41").is_err());
+        assert!(parse_message_id("<@example.com>").is_err());
+        assert!(parse_message_id("<a b@example.com>").is_err());
+    }
+
+    #[test]
+    fn test_state() {
+        let name = env::temp_dir().join(format!(
+            "sender-thread-{}.json",
+            &build_boundary().unwrap()[..16]
+        ));
+        let name = name.to_str().unwrap();
+
+        assert!(load_state(name).unwrap().is_empty());
+
+        let mut state = State::new();
+        state.insert(
+            "change-42".to_string(),
+            "<build-42@example.com>".to_string(),
+        );
+        save_state(name, &state).unwrap();
+        assert_eq!(load_state(name).unwrap(), state);
+
+        fs::write(name, "[]").unwrap();
+        assert!(load_state(name).is_err());
+        fs::remove_file(name).unwrap();
+    }
+}